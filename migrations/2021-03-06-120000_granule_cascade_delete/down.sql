@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+alter table granule
+    drop constraint granule_experiment_id_fkey,
+    add constraint granule_experiment_id_fkey
+        foreign key (experiment_id) references experiment(id);
@@ -0,0 +1,5 @@
+-- Removing an experiment should also remove its granules
+alter table granule
+    drop constraint granule_experiment_id_fkey,
+    add constraint granule_experiment_id_fkey
+        foreign key (experiment_id) references experiment(id) on delete cascade;
@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+alter table granule drop column valid;
+alter table granule add column valid boolean not null default false;
+
+update granule set valid = true where status = 'accepted';
+
+alter table granule
+    drop column status,
+    drop column rejection_reason;
+
+drop type if exists review_status;
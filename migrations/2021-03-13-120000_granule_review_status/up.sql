@@ -0,0 +1,13 @@
+-- Replace the valid flag with a review status, so rejected granules can be told apart from
+-- those that have not been looked at yet
+create type review_status as enum ('unreviewed', 'accepted', 'rejected');
+
+alter table granule
+    add column status review_status not null default 'unreviewed',
+    add column rejection_reason text;
+
+update granule set status = 'accepted' where valid;
+
+-- Keep valid around for older clients, derived from the status
+alter table granule drop column valid;
+alter table granule add column valid boolean generated always as (status = 'accepted') stored;
@@ -0,0 +1,16 @@
+-- Every review decision made on a granule
+--
+-- The events outlive the granules and experiments they describe, so there is no foreign key
+-- and deleting an experiment doesn't remove the record of how its data was reviewed
+create table granule_review_event (
+    id serial primary key,
+    experiment_id integer not null,
+    granule_id integer not null,
+    reviewer varchar(150) not null,
+    created_at timestamptz not null default now(),
+    old_status review_status not null,
+    new_status review_status not null,
+    reason text
+);
+
+CREATE INDEX granule_review_event_granule_index ON granule_review_event (experiment_id, granule_id);
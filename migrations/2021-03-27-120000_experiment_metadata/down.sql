@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+drop trigger if exists experiment_set_updated_at on experiment;
+drop function if exists set_updated_at();
+
+alter table experiment
+    drop column if exists created_at,
+    drop column if exists updated_at,
+    drop column if exists description,
+    drop column if exists acquired_on,
+    drop column if exists conditions;
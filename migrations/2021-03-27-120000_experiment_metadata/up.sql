@@ -0,0 +1,21 @@
+-- Describe how and when an experiment was run
+alter table experiment
+    add column created_at timestamptz not null default now(),
+    add column updated_at timestamptz not null default now(),
+    add column description text,
+    add column acquired_on date,
+    add column conditions jsonb not null default '{}';
+
+-- Keep updated_at current whichever client changes the row
+create function set_updated_at() returns trigger as $$
+begin
+    new.updated_at = now();
+    return new;
+end;
+$$ language plpgsql;
+
+create trigger experiment_set_updated_at before update on experiment
+    for each row execute function set_updated_at();
+
+CREATE INDEX experiment_acquired_on_index ON experiment (acquired_on);
+CREATE INDEX experiment_conditions_index ON experiment USING gin (conditions);
@@ -0,0 +1,12 @@
+-- This file should undo anything in `up.sql`
+alter table experiment add column author varchar(150);
+
+update experiment set author = author.name from author where author.id = experiment.author_id;
+
+alter table experiment
+    alter column author set not null,
+    drop column author_id;
+
+drop table if exists author;
+
+CREATE INDEX experiment_lower_author_index ON experiment (lower(author));
@@ -0,0 +1,30 @@
+-- Authors were free text on each experiment, make them rows of their own
+create table author (
+    id serial primary key,
+    name varchar(150) not null
+);
+
+CREATE UNIQUE INDEX author_lower_name_index ON author (lower(name));
+
+-- Spellings that only differ by case or spacing are the same author, the name of their first
+-- experiment is kept. Names that really differ, like "J. Smith" and "Jane Smith", are left
+-- to be merged by hand.
+insert into author (name)
+    select distinct on (lower(name)) name
+    from (select id, regexp_replace(trim(author), '\s+', ' ', 'g') as name from experiment) as names
+    order by lower(name), id;
+
+alter table experiment add column author_id integer;
+
+update experiment set author_id = author.id
+    from author
+    where lower(author.name) = lower(regexp_replace(trim(experiment.author), '\s+', ' ', 'g'));
+
+alter table experiment
+    alter column author_id set not null,
+    add constraint experiment_author_id_fkey foreign key (author_id) references author(id);
+
+drop index experiment_lower_author_index;
+alter table experiment drop column author;
+
+CREATE INDEX experiment_author_index ON experiment (author_id);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+drop index if exists author_name_trigram_index;
+drop index if exists experiment_title_search_index;
+drop extension if exists pg_trgm;
@@ -0,0 +1,5 @@
+-- Ranked search over experiment titles and author names
+create extension if not exists pg_trgm;
+
+CREATE INDEX experiment_title_search_index ON experiment USING gin (to_tsvector('english', title));
+CREATE INDEX author_name_trigram_index ON author USING gin (name gin_trgm_ops);
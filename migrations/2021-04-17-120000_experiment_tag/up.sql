@@ -0,0 +1,17 @@
+-- Ad-hoc labels for grouping experiments, such as "arsenite" or "pilot"
+create table tag (
+    id serial primary key,
+    name varchar(50) not null
+);
+
+CREATE UNIQUE INDEX tag_name_index ON tag (name);
+
+create table experiment_tag (
+    experiment_id integer not null,
+    tag_id integer not null,
+    primary key (experiment_id, tag_id),
+    foreign key (experiment_id) references experiment(id) on delete cascade,
+    foreign key (tag_id) references tag(id) on delete cascade
+);
+
+CREATE INDEX experiment_tag_tag_index ON experiment_tag (tag_id);
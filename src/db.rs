@@ -1,119 +1,791 @@
 //! Handle the gathering of data from the postgres database
 use crate::errors::{AppError, AppErrorType};
-use crate::models::{CreateGranule, Experiment, Granule};
-use deadpool_postgres::Client;
+use crate::models::{
+    Author, AuthorSummary, CreateExperiment, CreateGranule, DeleteExperimentResponse, Experiment,
+    ExperimentMatch, ExperimentQuery, Granule, GranuleQuery, GranuleSort, GranuleStats,
+    MergeAuthorsResponse, Page, PageQuery, ReviewEvent, ReviewStatus, TagMatch, UpdateExperiment,
+};
+use deadpool_postgres::{Client, Transaction};
+use futures::future::try_join_all;
 use tokio_pg_mapper::FromTokioPostgresRow;
 
-pub async fn get_experiments(client: &Client) -> Result<Vec<Experiment>, AppError> {
+/// Experiment rows from `source` along with the name of their author and their tags, as the
+/// `Experiment` model expects
+fn experiment_select(source: &str) -> String {
+    format!(
+        "select {source}.*, author.name as author,
+            array(
+                select tag.name from experiment_tag join tag on tag.id = experiment_tag.tag_id
+                where experiment_tag.experiment_id = {source}.id
+                order by tag.name
+            ) as tags
+        from {source} join author on author.id = {source}.author_id",
+        source = source
+    )
+}
+
+/// Wrap a statement that returns experiment rows so their author and tags are included
+fn with_experiment_details(query: &str) -> String {
+    format!(
+        "with changed as ({}) {}",
+        query,
+        experiment_select("changed")
+    )
+}
+
+/// Tidy the spacing of a name, so authors can be matched however they were typed
+fn clean_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Id of the author with the given name, ignoring case, creating the author if there is none
+async fn find_or_create_author(transaction: &Transaction<'_>, name: &str) -> Result<i32, AppError> {
+    // The no-op update makes the existing row be returned on a conflict
+    let row = transaction
+        .query_one(
+            "insert into author (name) values ($1)
+            on conflict ((lower(name))) do update set name = author.name
+            returning id",
+            &[&clean_name(name)],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    row.try_get(0).map_err(AppError::postgres_error)
+}
+
+pub async fn get_experiments(
+    client: &Client,
+    filter: &ExperimentQuery,
+    page: &PageQuery,
+) -> Result<Page<Experiment>, AppError> {
+    let filters = "($1::date is null or experiment.acquired_on >= $1)
+        and ($2::date is null or experiment.acquired_on <= $2)
+        and ($3::text[] is null or experiment.conditions ?& $3)
+        and ($4::jsonb is null or experiment.conditions @> $4)
+        and ($5::text[] is null or (
+            select count(*) from experiment_tag join tag on tag.id = experiment_tag.tag_id
+            where experiment_tag.experiment_id = experiment.id and tag.name = any($5)
+        ) >= case when $6 then cardinality($5) else 1 end)";
+    let condition_keys = filter.condition_keys();
+    let conditions = filter.conditions();
+    let tags = if filter.tags.is_empty() {
+        None
+    } else {
+        Some(&filter.tags)
+    };
+    let all_tags = filter.tag_match == TagMatch::All;
+
+    // Newest experiments first, so the cursor walks downwards through the ids
+    let statement = client
+        .prepare(&format!(
+            "{} where {} and ($7::int4 is null or experiment.id < $7) order by experiment.id desc limit $8",
+            experiment_select("experiment"),
+            filters
+        ))
+        .await
+        .map_err(AppError::postgres_error)?;
+    let limit = page.limit();
+    let experiments = client
+        .query(
+            &statement,
+            &[
+                &filter.acquired_from,
+                &filter.acquired_to,
+                &condition_keys,
+                &conditions,
+                &tags,
+                &all_tags,
+                &page.after,
+                &(limit + 1),
+            ],
+        )
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| Experiment::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<Experiment>, AppError>>()?;
+
+    let mut result = Page::from_rows(experiments, limit, |experiment| experiment.id);
+    if page.include_total {
+        let row = client
+            .query_one(
+                format!("select count(*) from experiment where {}", filters).as_str(),
+                &[
+                    &filter.acquired_from,
+                    &filter.acquired_to,
+                    &condition_keys,
+                    &conditions,
+                    &tags,
+                    &all_tags,
+                ],
+            )
+            .await
+            .map_err(AppError::postgres_error)?;
+        result.total = Some(row.try_get(0).map_err(AppError::postgres_error)?);
+    }
+
+    Ok(result)
+}
+
+pub async fn get_experiment(client: &Client, experiment_id: i32) -> Result<Experiment, AppError> {
     let statement = client
-        .prepare("select * from experiment order by id desc")
+        .prepare(&format!(
+            "{} where experiment.id = $1",
+            experiment_select("experiment")
+        ))
         .await
-        .map_err(|err| AppError::db_error(err))?;
+        .map_err(AppError::postgres_error)?;
+
     let experiment = client
-        .query(&statement, &[])
+        .query(&statement, &[&experiment_id])
         .await
-        .expect("Error getting experiment")
+        .map_err(AppError::postgres_error)?
         .iter()
-        .map(|row| Experiment::from_row_ref(row).expect("Unable to unwrap experiment"))
-        .collect::<Vec<Experiment>>();
+        .map(|row| Experiment::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<Experiment>, AppError>>()?
+        .pop()
+        .ok_or_else(|| {
+            AppError::not_found(
+                "experiment_not_found",
+                format!("Experiment {} not found", experiment_id),
+            )
+        })?;
 
     Ok(experiment)
 }
 
-pub async fn get_granules(client: &Client, experiment_id: i32) -> Result<Vec<Granule>, AppError> {
+/// Where clause that selects the granules after the cursor for the given sort order
+///
+/// The cursor is always a granule id, when sorting by area we look up that granule's area so the
+/// ids can be used to break ties. Granules without an area come last in either direction, and
+/// the cursor has to belong to the experiment being listed, see `check_granule_cursor`.
+fn granule_cursor_clause(sort: GranuleSort) -> &'static str {
+    match sort {
+        GranuleSort::Id => "id > $7",
+        GranuleSort::Area => {
+            "case when (select area from granule where id = $7 and experiment_id = $1) is null
+                then area is null and id > $7
+                else area is null or (area, id) > (select area, id from granule where id = $7 and experiment_id = $1)
+            end"
+        }
+        GranuleSort::AreaDesc => {
+            "case when (select area from granule where id = $7 and experiment_id = $1) is null
+                then area is null and id < $7
+                else area is null or (area, id) < (select area, id from granule where id = $7 and experiment_id = $1)
+            end"
+        }
+    }
+}
+
+/// Refuse an area sort cursor from another experiment, which has no place in this listing
+async fn check_granule_cursor(
+    client: &Client,
+    experiment_id: i32,
+    sort: GranuleSort,
+    after: Option<i32>,
+) -> Result<(), AppError> {
+    let after = match after {
+        Some(after) if sort != GranuleSort::Id => after,
+        _ => return Ok(()),
+    };
+    let row = client
+        .query_opt(
+            "select 1 from granule where id = $1 and experiment_id = $2",
+            &[&after, &experiment_id],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+    match row {
+        Some(_) => Ok(()),
+        None => Err(AppError::bad_request(format!(
+            "Cursor {} is not a granule of experiment {}",
+            after, experiment_id
+        ))),
+    }
+}
+
+fn granule_order_clause(sort: GranuleSort) -> &'static str {
+    match sort {
+        GranuleSort::Id => "id",
+        GranuleSort::Area => "area nulls last, id",
+        GranuleSort::AreaDesc => "area desc nulls last, id desc",
+    }
+}
+
+pub async fn get_granules(
+    client: &Client,
+    experiment_id: i32,
+    filter: &GranuleQuery,
+    page: &PageQuery,
+) -> Result<Page<Granule>, AppError> {
+    // Area bounds never match a granule without an area
+    let filters = "experiment_id = $1
+        and ($2::bool is null or valid = $2)
+        and ($3::float4 is null or area >= $3)
+        and ($4::float4 is null or area <= $4)
+        and ($5::review_status is null or status = $5)
+        and ($6::bool is null or (area is not null) = $6)";
+    let query = format!(
+        "select * from granule where {} and ($7::int4 is null or {}) order by {} limit $8",
+        filters,
+        granule_cursor_clause(filter.sort),
+        granule_order_clause(filter.sort)
+    );
+    check_granule_cursor(client, experiment_id, filter.sort, page.after).await?;
     let statement = client
-        .prepare("select * from granule where experiment_id = $1 order by id")
+        .prepare(&query)
         .await
-        .map_err(|err| AppError::db_error(err))?;
+        .map_err(AppError::postgres_error)?;
 
-    let granule = client
-        .query(&statement, &[&experiment_id])
+    let limit = page.limit();
+    let granules = client
+        .query(
+            &statement,
+            &[
+                &experiment_id,
+                &filter.valid,
+                &filter.min_area,
+                &filter.max_area,
+                &filter.status,
+                &filter.has_area,
+                &page.after,
+                &(limit + 1),
+            ],
+        )
         .await
-        .map_err(|err| AppError::db_error(err))?
+        .map_err(AppError::postgres_error)?
         .iter()
-        .map(|row| Granule::from_row_ref(row).expect("Unable to unwrap granule"))
-        .collect::<Vec<Granule>>();
+        .map(|row| Granule::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<Granule>, AppError>>()?;
 
-    Ok(granule)
+    let mut result = Page::from_rows(granules, limit, |granule| granule.id);
+    if page.include_total {
+        let row = client
+            .query_one(
+                format!("select count(*) from granule where {}", filters).as_str(),
+                &[
+                    &experiment_id,
+                    &filter.valid,
+                    &filter.min_area,
+                    &filter.max_area,
+                    &filter.status,
+                    &filter.has_area,
+                ],
+            )
+            .await
+            .map_err(AppError::postgres_error)?;
+        result.total = Some(row.try_get(0).map_err(AppError::postgres_error)?);
+    }
+
+    Ok(result)
 }
 
-pub async fn create_experiment(
+pub async fn get_granule_stats(
     client: &Client,
-    title: String,
-    author: String,
-) -> Result<Experiment, AppError> {
+    experiment_id: i32,
+) -> Result<GranuleStats, AppError> {
+    // Check the experiment exists so an empty one isn't mistaken for a missing one
+    get_experiment(client, experiment_id).await?;
+
     let statement = client
         .prepare(
-            "insert into experiment (title, author) values ($1, $2) returning id, title, author",
+            "select count(*) as count,
+                count(area) as with_area,
+                count(*) - count(area) as missing_area,
+                min(area) as min_area,
+                max(area) as max_area,
+                avg(area) as mean_area
+            from granule where experiment_id = $1",
         )
         .await
-        .map_err(|err| AppError::db_error(err))?;
+        .map_err(AppError::postgres_error)?;
 
-    let experiment = client
-        .query(&statement, &[&title, &author])
+    let row = client
+        .query_one(&statement, &[&experiment_id])
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    GranuleStats::from_row_ref(&row).map_err(AppError::db_error)
+}
+
+pub async fn create_experiment(
+    client: &mut Client,
+    experiment: &CreateExperiment,
+) -> Result<Experiment, AppError> {
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(AppError::postgres_error)?;
+    let author_id = find_or_create_author(&transaction, &experiment.author).await?;
+
+    let statement = transaction
+        .prepare(&with_experiment_details(
+            "insert into experiment (title, author_id, description, acquired_on, conditions)
+            values ($1, $2, $3, $4, coalesce($5, '{}'::jsonb)) returning *",
+        ))
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let experiment = transaction
+        .query(
+            &statement,
+            &[
+                &experiment.title,
+                &author_id,
+                &experiment.description,
+                &experiment.acquired_on,
+                &experiment.conditions,
+            ],
+        )
         .await
-        .map_err(|err| AppError::db_error(err))?
+        .map_err(AppError::postgres_error)?
         .iter()
-        .map(|row| Experiment::from_row_ref(row).unwrap())
-        .collect::<Vec<Experiment>>()
+        .map(|row| Experiment::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<Experiment>, AppError>>()?
         .pop()
         .ok_or(AppError {
             message: Some("Unable to make create experiment".to_string()),
             cause: None,
             error_type: AppErrorType::DbError,
+            code: None,
         })?;
 
+    transaction
+        .commit()
+        .await
+        .map_err(AppError::postgres_error)?;
     Ok(experiment)
 }
 
+pub async fn update_experiment(
+    client: &mut Client,
+    experiment_id: i32,
+    changes: &UpdateExperiment,
+) -> Result<Experiment, AppError> {
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(AppError::postgres_error)?;
+    let author_id = match &changes.author {
+        Some(author) => Some(find_or_create_author(&transaction, author).await?),
+        None => None,
+    };
+
+    // Fields that are not provided keep their current value, while the optional ones can also
+    // be cleared, so whether they were given is passed separately from their new value
+    let statement = transaction
+        .prepare(&with_experiment_details(
+            "update experiment set
+                title = coalesce($2, title),
+                author_id = coalesce($3, author_id),
+                description = case when $7 then $4 else description end,
+                acquired_on = case when $8 then $5 else acquired_on end,
+                conditions = case when $9 then coalesce($6, '{}'::jsonb) else conditions end
+            where id = $1 returning *",
+        ))
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let description = changes.description.clone().flatten();
+    let acquired_on = changes.acquired_on.flatten();
+    let conditions = changes.conditions.clone().flatten();
+    let experiment = transaction
+        .query(
+            &statement,
+            &[
+                &experiment_id,
+                &changes.title,
+                &author_id,
+                &description,
+                &acquired_on,
+                &conditions,
+                &changes.description.is_some(),
+                &changes.acquired_on.is_some(),
+                &changes.conditions.is_some(),
+            ],
+        )
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| Experiment::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<Experiment>, AppError>>()?
+        .pop()
+        .ok_or_else(|| {
+            AppError::not_found(
+                "experiment_not_found",
+                format!("Experiment {} not found", experiment_id),
+            )
+        })?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(AppError::postgres_error)?;
+    Ok(experiment)
+}
+
+/// Remove an experiment along with all of its granules
+///
+/// Both deletes happen in a single transaction, with `dry_run` the transaction is rolled back so
+/// the returned counts are a preview of what would be removed.
+pub async fn delete_experiment(
+    client: &mut Client,
+    experiment_id: i32,
+    dry_run: bool,
+) -> Result<DeleteExperimentResponse, AppError> {
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    // Lock the experiment so no granules can be added while we are deleting
+    let found = transaction
+        .query(
+            "select id from experiment where id = $1 for update",
+            &[&experiment_id],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+    if found.is_empty() {
+        return Err(AppError::not_found(
+            "experiment_not_found",
+            format!("Experiment {} not found", experiment_id),
+        ));
+    }
+
+    let granules_deleted = transaction
+        .execute(
+            "delete from granule where experiment_id = $1",
+            &[&experiment_id],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+    transaction
+        .execute("delete from experiment where id = $1", &[&experiment_id])
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    if dry_run {
+        transaction
+            .rollback()
+            .await
+            .map_err(AppError::postgres_error)?;
+    } else {
+        transaction
+            .commit()
+            .await
+            .map_err(AppError::postgres_error)?;
+    }
+
+    Ok(DeleteExperimentResponse {
+        experiment_id,
+        granules_deleted,
+        dry_run,
+    })
+}
+
 pub async fn create_granule(
     client: &Client,
     granule_cmd: CreateGranule,
     experiment_id: i32,
 ) -> Result<Granule, AppError> {
-    let CreateGranule { valid, area } = granule_cmd;
+    let status = granule_cmd.initial_status();
+    let CreateGranule { area, .. } = granule_cmd;
     let statement = client
         .prepare(
-            "insert into granule (valid, area, experiment_id) values ($1, $2, $3) returning id, valid, area, experiment_id",
+            "insert into granule (status, area, experiment_id) values ($1, $2, $3) returning *",
         )
         .await
-        .map_err(|err| AppError::db_error(err))?;
+        .map_err(AppError::postgres_error)?;
 
     let granule = client
-        .query(&statement, &[&valid, &area, &experiment_id])
+        .query(&statement, &[&status, &area, &experiment_id])
         .await
-        .map_err(|err| AppError::db_error(err))?
+        .map_err(AppError::postgres_error)?
         .iter()
-        .map(|row| Granule::from_row_ref(row).unwrap())
-        .collect::<Vec<Granule>>()
+        .map(|row| Granule::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<Granule>, AppError>>()?
         .pop()
         .ok_or(AppError {
             message: Some("Unable to add granule".to_string()),
             cause: None,
             error_type: AppErrorType::DbError,
+            code: None,
         })?;
 
     Ok(granule)
 }
 
+/// Insert many granules in a single transaction, returning their ids in order
+///
+/// If any insert fails the whole batch is rolled back.
+pub async fn create_granules(
+    client: &mut Client,
+    granules: &[CreateGranule],
+    experiment_id: i32,
+) -> Result<Vec<i32>, AppError> {
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    // Stop the experiment being deleted part way through the upload
+    let found = transaction
+        .query(
+            "select id from experiment where id = $1 for share",
+            &[&experiment_id],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+    if found.is_empty() {
+        return Err(AppError::not_found(
+            "experiment_not_found",
+            format!("Experiment {} not found", experiment_id),
+        ));
+    }
+
+    let statement = transaction
+        .prepare(
+            "insert into granule (status, area, experiment_id) values ($1, $2, $3) returning id",
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    // The inserts are pipelined over the connection rather than waiting on each in turn
+    let (transaction_ref, statement) = (&transaction, &statement);
+    let inserts = granules.iter().map(|granule| async move {
+        let status = granule.initial_status();
+        transaction_ref
+            .query_one(statement, &[&status, &granule.area, &experiment_id])
+            .await
+    });
+    let ids = try_join_all(inserts)
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| row.try_get(0).map_err(AppError::postgres_error))
+        .collect::<Result<Vec<i32>, AppError>>()?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    Ok(ids)
+}
+
+/// Lock a granule for review and return it as it currently is
+async fn lock_granule(
+    transaction: &Transaction<'_>,
+    experiment_id: i32,
+    granule_id: i32,
+) -> Result<Option<Granule>, AppError> {
+    let granule = transaction
+        .query(
+            "select * from granule where experiment_id = $1 and id = $2 for update",
+            &[&experiment_id, &granule_id],
+        )
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| Granule::from_row_ref(row).map_err(AppError::db_error))
+        .next()
+        .transpose()?;
+
+    Ok(granule)
+}
+
+/// Add a decision to the review history of a granule
+async fn record_review(
+    transaction: &Transaction<'_>,
+    experiment_id: i32,
+    granule_id: i32,
+    reviewer: &str,
+    old_status: ReviewStatus,
+    new_status: ReviewStatus,
+    reason: &Option<String>,
+) -> Result<(), AppError> {
+    transaction
+        .execute(
+            "insert into granule_review_event (experiment_id, granule_id, reviewer, old_status, new_status, reason) values ($1, $2, $3, $4, $5, $6)",
+            &[&experiment_id, &granule_id, &reviewer, &old_status, &new_status, reason],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    Ok(())
+}
+
 pub async fn mark_granule_valid(
-    client: &Client,
+    client: &mut Client,
     experiment_id: i32,
     granule_id: i32,
+    reviewer: &str,
 ) -> Result<bool, AppError> {
-    let query =
-        "update granule set valid = true where experiment_id = $1 and id = $2 and valid = false";
-    let statement = client
-        .prepare(query)
+    let transaction = client
+        .transaction()
         .await
-        .map_err(|err| AppError::db_error(err))?;
+        .map_err(AppError::postgres_error)?;
+
+    // Only granules that have not already been accepted are updated
+    let old_status = match lock_granule(&transaction, experiment_id, granule_id).await? {
+        Some(granule) if granule.status != ReviewStatus::Accepted => granule.status,
+        _ => return Ok(false),
+    };
 
-    let result = client
+    let query = "update granule set status = 'accepted', rejection_reason = null
+        where experiment_id = $1 and id = $2";
+    let statement = transaction
+        .prepare(query)
+        .await
+        .map_err(AppError::postgres_error)?;
+    transaction
         .execute(&statement, &[&experiment_id, &granule_id])
         .await
-        .map_err(|err| AppError::db_error(err))?;
+        .map_err(AppError::postgres_error)?;
+
+    record_review(
+        &transaction,
+        experiment_id,
+        granule_id,
+        reviewer,
+        old_status,
+        ReviewStatus::Accepted,
+        &None,
+    )
+    .await?;
+    transaction
+        .commit()
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    Ok(true)
+}
+
+/// Record the outcome of reviewing a granule
+///
+/// The rejection reason is only kept for rejected granules, it is cleared otherwise. A decision
+/// that matches the granule's current status and reason leaves it, and its history, untouched.
+pub async fn set_granule_status(
+    client: &mut Client,
+    experiment_id: i32,
+    granule_id: i32,
+    status: ReviewStatus,
+    reason: Option<String>,
+    reviewer: &str,
+) -> Result<Granule, AppError> {
+    let reason = match status {
+        ReviewStatus::Rejected => reason,
+        _ => None,
+    };
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let current = lock_granule(&transaction, experiment_id, granule_id)
+        .await?
+        .ok_or_else(|| {
+            AppError::not_found(
+                "granule_not_found",
+                format!(
+                    "Granule {} not found in experiment {}",
+                    granule_id, experiment_id
+                ),
+            )
+        })?;
+
+    // Repeating the same decision changes nothing, so it isn't added to the history
+    if current.status == status && current.rejection_reason == reason {
+        return Ok(current);
+    }
+    let old_status = current.status;
+
+    let statement = transaction
+        .prepare(
+            "update granule set status = $3, rejection_reason = $4 where experiment_id = $1 and id = $2 returning *",
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let granule = transaction
+        .query(&statement, &[&experiment_id, &granule_id, &status, &reason])
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| Granule::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<Granule>, AppError>>()?
+        .pop()
+        .ok_or(AppError {
+            message: Some("Unable to update granule".to_string()),
+            cause: None,
+            error_type: AppErrorType::DbError,
+            code: None,
+        })?;
+
+    record_review(
+        &transaction,
+        experiment_id,
+        granule_id,
+        reviewer,
+        old_status,
+        status,
+        &reason,
+    )
+    .await?;
+    transaction
+        .commit()
+        .await
+        .map_err(AppError::postgres_error)?;
 
-    Ok(result == 1)
+    Ok(granule)
+}
+
+/// Every review decision made on a granule, oldest first
+///
+/// The history is kept after the granule or its experiment is deleted, so it can still be read.
+pub async fn get_granule_history(
+    client: &Client,
+    experiment_id: i32,
+    granule_id: i32,
+) -> Result<Vec<ReviewEvent>, AppError> {
+    let statement = client
+        .prepare(
+            "select * from granule_review_event where experiment_id = $1 and granule_id = $2 order by created_at, id",
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let events = client
+        .query(&statement, &[&experiment_id, &granule_id])
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| ReviewEvent::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<ReviewEvent>, AppError>>()?;
+
+    // A granule that has never been reviewed has an empty history, one that never existed has none
+    if events.is_empty() {
+        let found = client
+            .query(
+                "select id from granule where experiment_id = $1 and id = $2",
+                &[&experiment_id, &granule_id],
+            )
+            .await
+            .map_err(AppError::postgres_error)?;
+        if found.is_empty() {
+            return Err(AppError::not_found(
+                "granule_not_found",
+                format!(
+                    "Granule {} not found in experiment {}",
+                    granule_id, experiment_id
+                ),
+            ));
+        }
+    }
+
+    Ok(events)
 }
 
 pub async fn get_authors_experiment(
@@ -121,17 +793,298 @@ pub async fn get_authors_experiment(
     author: String,
 ) -> Result<Vec<Experiment>, AppError> {
     let statement = client
-        .prepare("select * from experiment where lower(author) = lower($1) order by id")
+        .prepare(&format!(
+            "{} where lower(author.name) = lower($1) order by experiment.id",
+            experiment_select("experiment")
+        ))
         .await
-        .map_err(|err| AppError::db_error(err))?;
+        .map_err(AppError::postgres_error)?;
 
     let experiments = client
-        .query(&statement, &[&author])
+        .query(&statement, &[&clean_name(&author)])
         .await
-        .map_err(|err| AppError::db_error(err))?
+        .map_err(AppError::postgres_error)?
         .iter()
-        .map(|row| Experiment::from_row_ref(row).expect("Unable to unwrap experiments"))
-        .collect::<Vec<Experiment>>();
+        .map(|row| Experiment::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<Experiment>, AppError>>()?;
 
     Ok(experiments)
 }
+
+/// How closely a search has to match part of an author's name, low enough to allow for typos
+const AUTHOR_SIMILARITY_THRESHOLD: &str = "0.3";
+
+/// Experiments whose title matches the search words, or whose author's name is close to them
+///
+/// Titles use full text search, so "granule" also finds "granules", while authors are compared by
+/// trigrams, so "Smth" still finds "Jane Smith". Both scores are added to rank the results.
+pub async fn search_experiments(
+    client: &mut Client,
+    search: &str,
+    limit: i64,
+) -> Result<Vec<ExperimentMatch>, AppError> {
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    // Set for this transaction only, so `<%` can use the trigram index with our threshold
+    transaction
+        .execute(
+            "select set_config('pg_trgm.word_similarity_threshold', $1, true)",
+            &[&AUTHOR_SIMILARITY_THRESHOLD],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let statement = transaction
+        .prepare(&format!(
+            "select * from (
+                select matches.*,
+                    ts_rank(to_tsvector('english', title), websearch_to_tsquery('english', $1))
+                        + word_similarity($1, author) as rank
+                from ({}
+                    where to_tsvector('english', experiment.title) @@ websearch_to_tsquery('english', $1)
+                        or $1 <% author.name
+                ) as matches
+            ) as ranked
+            order by rank desc, id desc
+            limit $2",
+            experiment_select("experiment")
+        ))
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let matches = transaction
+        .query(&statement, &[&search, &limit])
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| {
+            Ok(ExperimentMatch {
+                experiment: Experiment::from_row_ref(row).map_err(AppError::db_error)?,
+                rank: row.try_get("rank").map_err(AppError::postgres_error)?,
+            })
+        })
+        .collect::<Result<Vec<ExperimentMatch>, AppError>>()?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(AppError::postgres_error)?;
+    Ok(matches)
+}
+
+pub async fn get_authors(client: &Client) -> Result<Vec<AuthorSummary>, AppError> {
+    let statement = client
+        .prepare(
+            "select author.id, author.name,
+                count(distinct experiment.id) as experiment_count,
+                count(granule.id) as granule_count
+            from author
+                left join experiment on experiment.author_id = author.id
+                left join granule on granule.experiment_id = experiment.id
+            group by author.id
+            order by lower(author.name), author.id",
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let authors = client
+        .query(&statement, &[])
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| AuthorSummary::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<AuthorSummary>, AppError>>()?;
+
+    Ok(authors)
+}
+
+/// Change the name of an author, which changes it on all of their experiments at once
+pub async fn rename_author(
+    client: &Client,
+    author_id: i32,
+    name: &str,
+) -> Result<Author, AppError> {
+    let statement = client
+        .prepare("update author set name = $2 where id = $1 returning *")
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let author = client
+        .query(&statement, &[&author_id, &clean_name(name)])
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| Author::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<Author>, AppError>>()?
+        .pop()
+        .ok_or_else(|| {
+            AppError::not_found(
+                "author_not_found",
+                format!("Author {} not found", author_id),
+            )
+        })?;
+
+    Ok(author)
+}
+
+/// Move every experiment of the `from` authors to `author_id` and remove the `from` authors
+///
+/// Either all of the authors are merged or, if any of them is missing, none are.
+pub async fn merge_authors(
+    client: &mut Client,
+    author_id: i32,
+    from: &[i32],
+) -> Result<MergeAuthorsResponse, AppError> {
+    if from.contains(&author_id) {
+        return Err(AppError::bad_request(
+            "An author cannot be merged into itself",
+        ));
+    }
+    let mut merged = from.to_vec();
+    merged.sort_unstable();
+    merged.dedup();
+
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let author = transaction
+        .query(
+            "select * from author where id = $1 for update",
+            &[&author_id],
+        )
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| Author::from_row_ref(row).map_err(AppError::db_error))
+        .collect::<Result<Vec<Author>, AppError>>()?
+        .pop()
+        .ok_or_else(|| {
+            AppError::not_found(
+                "author_not_found",
+                format!("Author {} not found", author_id),
+            )
+        })?;
+
+    let found = transaction
+        .query(
+            "select id from author where id = any($1) for update",
+            &[&merged],
+        )
+        .await
+        .map_err(AppError::postgres_error)?
+        .iter()
+        .map(|row| row.try_get(0).map_err(AppError::postgres_error))
+        .collect::<Result<Vec<i32>, AppError>>()?;
+    if let Some(missing) = merged.iter().find(|id| !found.contains(id)) {
+        return Err(AppError::not_found(
+            "author_not_found",
+            format!("Author {} not found", missing),
+        ));
+    }
+
+    let experiments_moved = transaction
+        .execute(
+            "update experiment set author_id = $1 where author_id = any($2)",
+            &[&author_id, &merged],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+    transaction
+        .execute("delete from author where id = any($1)", &[&merged])
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(AppError::postgres_error)?;
+    Ok(MergeAuthorsResponse {
+        author,
+        merged,
+        experiments_moved,
+    })
+}
+
+/// Tag an experiment, tagging it again with the same tag has no effect
+pub async fn add_experiment_tag(
+    client: &mut Client,
+    experiment_id: i32,
+    tag: &str,
+) -> Result<Experiment, AppError> {
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    let found = transaction
+        .query(
+            "select id from experiment where id = $1 for share",
+            &[&experiment_id],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+    if found.is_empty() {
+        return Err(AppError::not_found(
+            "experiment_not_found",
+            format!("Experiment {} not found", experiment_id),
+        ));
+    }
+
+    // The no-op update makes the existing row be returned on a conflict
+    let row = transaction
+        .query_one(
+            "insert into tag (name) values ($1)
+            on conflict (name) do update set name = tag.name
+            returning id",
+            &[&tag],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+    let tag_id: i32 = row.try_get(0).map_err(AppError::postgres_error)?;
+    transaction
+        .execute(
+            "insert into experiment_tag (experiment_id, tag_id) values ($1, $2) on conflict do nothing",
+            &[&experiment_id, &tag_id],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(AppError::postgres_error)?;
+    get_experiment(client, experiment_id).await
+}
+
+pub async fn remove_experiment_tag(
+    client: &Client,
+    experiment_id: i32,
+    tag: &str,
+) -> Result<Experiment, AppError> {
+    let removed = client
+        .execute(
+            "delete from experiment_tag using tag
+            where tag.id = experiment_tag.tag_id
+                and experiment_tag.experiment_id = $1
+                and tag.name = $2",
+            &[&experiment_id, &tag],
+        )
+        .await
+        .map_err(AppError::postgres_error)?;
+
+    // Looking the experiment up tells a missing experiment apart from a missing tag
+    let experiment = get_experiment(client, experiment_id).await?;
+    if removed == 0 {
+        return Err(AppError::not_found(
+            "tag_not_found",
+            format!("Experiment {} is not tagged \"{}\"", experiment_id, tag),
+        ));
+    }
+
+    Ok(experiment)
+}
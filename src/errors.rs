@@ -1,11 +1,77 @@
-use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
-use serde::Serialize;
+use actix_web::{
+    error::ResponseError,
+    http::{header, StatusCode},
+    HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
 use std::fmt;
+use tokio_postgres::error::{DbError, SqlState};
+
+/// Media type of RFC 7807 problem responses
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Shape of the error bodies sent to users
+///
+/// `Legacy` is the original `{"error": ...}` body, kept while clients move to problem responses.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    #[default]
+    Problem,
+    Legacy,
+}
+
+/// Details of the request being handled that appear in its error responses
+#[derive(Clone)]
+pub struct ErrorContext {
+    pub request_id: String,
+    pub format: ErrorFormat,
+}
+
+tokio::task_local! {
+    /// Set for each request by the `RequestContext` middleware
+    pub static ERROR_CONTEXT: ErrorContext;
+}
+
+/// Messages for each invalid field of a request, keyed by the field name
+pub type FieldErrors = BTreeMap<String, Vec<String>>;
 
 #[derive(Debug)]
 pub enum AppErrorType {
     DbError,
     NotFoundError,
+    ValidationError(FieldErrors),
+    BadRequest,
+    Conflict,
+    RetryableError,
+}
+
+impl AppErrorType {
+    /// Stable machine readable code, used when the error does not give a more specific one
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppErrorType::DbError => "database_error",
+            AppErrorType::NotFoundError => "not_found",
+            AppErrorType::ValidationError(_) => "validation_failed",
+            AppErrorType::BadRequest => "bad_request",
+            AppErrorType::Conflict => "conflict",
+            AppErrorType::RetryableError => "retry_later",
+        }
+    }
+
+    /// Short summary that is the same for every error of this type
+    pub fn title(&self) -> &'static str {
+        match self {
+            AppErrorType::DbError => "Database error",
+            AppErrorType::NotFoundError => "Not found",
+            AppErrorType::ValidationError(_) => "Validation failed",
+            AppErrorType::BadRequest => "Bad request",
+            AppErrorType::Conflict => "Conflict",
+            AppErrorType::RetryableError => "Service unavailable",
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -13,6 +79,8 @@ pub struct AppError {
     pub message: Option<String>,
     pub cause: Option<String>,
     pub error_type: AppErrorType,
+    // More specific machine readable code, such as `experiment_not_found`
+    pub code: Option<&'static str>,
 }
 
 impl AppError {
@@ -24,17 +92,44 @@ impl AppError {
                 message: Some(message),
                 cause: _,
                 error_type: _,
+                code: _,
             } => message.clone(),
             AppError {
                 message: None,
                 cause: _,
                 error_type: AppErrorType::NotFoundError,
+                code: _,
             } => "The requested item was not found".to_string(),
             AppError {
                 message: None,
                 cause: _,
                 error_type: AppErrorType::DbError,
+                code: _,
             } => "Unexpected database error".to_string(),
+            AppError {
+                message: None,
+                cause: _,
+                error_type: AppErrorType::ValidationError(_),
+                code: _,
+            } => "The request contained invalid fields".to_string(),
+            AppError {
+                message: None,
+                cause: _,
+                error_type: AppErrorType::BadRequest,
+                code: _,
+            } => "The request could not be understood".to_string(),
+            AppError {
+                message: None,
+                cause: _,
+                error_type: AppErrorType::Conflict,
+                code: _,
+            } => "The item already exists".to_string(),
+            AppError {
+                message: None,
+                cause: _,
+                error_type: AppErrorType::RetryableError,
+                code: _,
+            } => "The database is busy, please try again".to_string(),
         }
     }
 
@@ -44,8 +139,92 @@ impl AppError {
             message: None,
             cause: Some(error.to_string()),
             error_type: AppErrorType::DbError,
+            code: None,
         }
     }
+
+    // Wrapper function for postgres errors, the SQLSTATE decides what the user is told
+    pub fn postgres_error(error: tokio_postgres::Error) -> Self {
+        let code = match error.code() {
+            Some(code) => code.clone(),
+            None => return AppError::db_error(error),
+        };
+
+        let mut app_error = sqlstate_error(&code);
+
+        // Keep the full details for the log
+        let detail = error
+            .source()
+            .and_then(|source| source.downcast_ref::<DbError>())
+            .and_then(|db_error| db_error.detail());
+        app_error.cause = Some(match detail {
+            Some(detail) => format!("{} ({}): {}", error, code.code(), detail),
+            None => format!("{} ({})", error, code.code()),
+        });
+
+        app_error
+    }
+
+    // Wrapper function for items that do not exist, with a code naming the kind of item
+    pub fn not_found(code: &'static str, message: impl ToString) -> Self {
+        AppError {
+            message: Some(message.to_string()),
+            cause: None,
+            error_type: AppErrorType::NotFoundError,
+            code: Some(code),
+        }
+    }
+
+    /// Machine readable code sent to the user
+    pub fn code(&self) -> &'static str {
+        self.code.unwrap_or_else(|| self.error_type.code())
+    }
+
+    // Wrapper function for requests that fail validation
+    pub fn validation_error(fields: FieldErrors) -> Self {
+        AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::ValidationError(fields),
+            code: None,
+        }
+    }
+
+    // Wrapper function for requests that are malformed
+    pub fn bad_request(message: impl ToString) -> Self {
+        AppError {
+            message: Some(message.to_string()),
+            cause: None,
+            error_type: AppErrorType::BadRequest,
+            code: None,
+        }
+    }
+}
+
+/// How a postgres SQLSTATE is reported to the user
+fn sqlstate_error(code: &SqlState) -> AppError {
+    let (message, error_type, code) = if *code == SqlState::FOREIGN_KEY_VIOLATION {
+        (
+            Some("A referenced item does not exist".to_string()),
+            AppErrorType::NotFoundError,
+            Some("referenced_item_not_found"),
+        )
+    } else if *code == SqlState::UNIQUE_VIOLATION {
+        (None, AppErrorType::Conflict, None)
+    } else if *code == SqlState::T_R_SERIALIZATION_FAILURE
+        || *code == SqlState::T_R_DEADLOCK_DETECTED
+    {
+        (None, AppErrorType::RetryableError, None)
+    } else {
+        (None, AppErrorType::DbError, None)
+    };
+
+    AppError {
+        message,
+        cause: None,
+        error_type,
+        code,
+    }
 }
 
 impl fmt::Display for AppError {
@@ -54,10 +233,27 @@ impl fmt::Display for AppError {
     }
 }
 
-// Error sent to the user
+// Error sent to the user in the legacy format
 #[derive(Serialize)]
 pub struct AppErrorResponse {
     pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<FieldErrors>,
+}
+
+/// Error sent to the user as an RFC 7807 problem
+#[derive(Serialize, Deserialize)]
+pub struct ProblemResponse {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<FieldErrors>,
 }
 
 impl ResponseError for AppError {
@@ -65,13 +261,43 @@ impl ResponseError for AppError {
         match self.error_type {
             AppErrorType::NotFoundError => StatusCode::NOT_FOUND,
             AppErrorType::DbError => StatusCode::INTERNAL_SERVER_ERROR,
+            AppErrorType::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppErrorType::BadRequest => StatusCode::BAD_REQUEST,
+            AppErrorType::Conflict => StatusCode::CONFLICT,
+            AppErrorType::RetryableError => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).json(AppErrorResponse {
-            error: self.message(),
-        })
+        let fields = match &self.error_type {
+            AppErrorType::ValidationError(fields) => Some(fields.clone()),
+            _ => None,
+        };
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppErrorType::RetryableError = self.error_type {
+            response.header(header::RETRY_AFTER, "1");
+        }
+
+        // Outside of a request, such as in tests, fall back to a problem without an id
+        let (request_id, format) = ERROR_CONTEXT
+            .try_with(|context| (Some(context.request_id.clone()), context.format))
+            .unwrap_or((None, ErrorFormat::Problem));
+
+        match format {
+            ErrorFormat::Legacy => response.json(AppErrorResponse {
+                error: self.message(),
+                fields,
+            }),
+            ErrorFormat::Problem => response.content_type(PROBLEM_JSON).json(ProblemResponse {
+                problem_type: format!("urn:granules:problem:{}", self.code()),
+                title: self.error_type.title().to_string(),
+                status: self.status_code().as_u16(),
+                detail: self.message(),
+                code: self.code().to_string(),
+                request_id,
+                fields,
+            }),
+        }
     }
 }
 
@@ -85,6 +311,7 @@ mod tests {
             message: None,
             cause: None,
             error_type: AppErrorType::DbError,
+            code: None,
         };
         assert_eq!(
             db_error.message(),
@@ -101,6 +328,7 @@ mod tests {
             message: Some(custom_msg.clone()),
             cause: None,
             error_type: AppErrorType::DbError,
+            code: None,
         };
         assert_eq!(
             db_error.message(),
@@ -108,4 +336,82 @@ mod tests {
             "Custom message should be used"
         );
     }
+
+    #[test]
+    fn test_sqlstate_mapping() {
+        let status = |code: &str| sqlstate_error(&SqlState::from_code(code)).status_code();
+        assert_eq!(status("23503"), StatusCode::NOT_FOUND);
+        assert_eq!(status("23505"), StatusCode::CONFLICT);
+        assert_eq!(status("40001"), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status("40P01"), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status("42P01"), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    /// Build the response inside a future so it can see the error context
+    async fn respond(error: &AppError) -> HttpResponse {
+        error.error_response()
+    }
+
+    #[actix_rt::test]
+    async fn test_problem_response() {
+        let error = AppError::not_found("experiment_not_found", "Experiment 1 not found");
+        let context = ErrorContext {
+            request_id: "abc123".to_string(),
+            format: ErrorFormat::Problem,
+        };
+        let response = ERROR_CONTEXT.scope(context, respond(&error)).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            PROBLEM_JSON,
+            "Problems should have their own content type"
+        );
+
+        let body = match response.body() {
+            actix_web::dev::ResponseBody::Body(actix_web::dev::Body::Bytes(bytes)) => bytes.clone(),
+            _ => panic!("Problem body should be bytes"),
+        };
+        let problem: ProblemResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem.status, 404);
+        assert_eq!(problem.code, "experiment_not_found");
+        assert_eq!(problem.detail, "Experiment 1 not found");
+        assert_eq!(problem.request_id, Some("abc123".to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn test_legacy_response() {
+        let error = AppError::bad_request("Unknown delimiter");
+        let context = ErrorContext {
+            request_id: "abc123".to_string(),
+            format: ErrorFormat::Legacy,
+        };
+        let response = ERROR_CONTEXT.scope(context, respond(&error)).await;
+        let body = match response.body() {
+            actix_web::dev::ResponseBody::Body(actix_web::dev::Body::Bytes(bytes)) => bytes.clone(),
+            _ => panic!("Error body should be bytes"),
+        };
+        assert_eq!(
+            body, r#"{"error":"Unknown delimiter"}"#,
+            "Legacy errors should keep their shape"
+        );
+    }
+
+    #[test]
+    fn test_validation_status() {
+        let mut fields = FieldErrors::new();
+        fields.insert("title".to_string(), vec!["must not be empty".to_string()]);
+
+        let error = AppError::validation_error(fields);
+        assert_eq!(
+            error.status_code(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Validation errors should be a 422"
+        );
+
+        let error = AppError::bad_request("Unknown delimiter");
+        assert_eq!(
+            error.status_code(),
+            StatusCode::BAD_REQUEST,
+            "Bad requests should be a 400"
+        );
+    }
 }
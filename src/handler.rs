@@ -3,10 +3,16 @@
 //! These functions are called by the server when a GET/PUT/POST request are sent
 
 use crate::db;
-use crate::errors::AppError;
+use crate::errors::{AppError, AppErrorType, FieldErrors};
 use crate::models::*;
-use actix_web::{get, post, put, web, HttpResponse, Responder};
+use crate::tabular;
+use crate::validation::{self, Validate};
+use actix_web::web::Bytes;
+use actix_web::{
+    delete, get, http::header, patch, post, put, web, HttpRequest, HttpResponse, Responder,
+};
 use deadpool_postgres::{Client, Pool};
+use futures::{future, stream, StreamExt};
 use serde::Serialize;
 use slog::{crit, error, o, Logger};
 
@@ -35,6 +41,101 @@ pub fn json_or_err<T: Serialize>(
         .map_err(log_error(log))
 }
 
+// Return a page of results as json, with a `Link` header pointing at the next page
+pub fn page_or_err<T: Serialize>(
+    req: &HttpRequest,
+    res: Result<Page<T>, AppError>,
+    log: Logger,
+) -> Result<impl Responder, AppError> {
+    res.map(|page| {
+        let mut response = HttpResponse::Ok();
+        if let Some(cursor) = page.next_cursor {
+            response.header(header::LINK, next_page_link(req, cursor));
+        }
+        response.json(page)
+    })
+    .map_err(log_error(log))
+}
+
+/// Link to the next page, keeping any other query parameters of the current request
+fn next_page_link(req: &HttpRequest, cursor: i32) -> String {
+    let mut params = req
+        .query_string()
+        .split('&')
+        .filter(|param| !param.is_empty() && !param.starts_with("after="))
+        .collect::<Vec<&str>>()
+        .join("&");
+    if !params.is_empty() {
+        params.push('&');
+    }
+    format!("<{}?{}after={}>; rel=\"next\"", req.path(), params, cursor)
+}
+
+/// Largest JSON body accepted unless `server.json_limit` is set, batch uploads can hold
+/// thousands of granules
+pub const JSON_PAYLOAD_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Largest table accepted when importing granules unless `server.csv_limit` is set
+pub const CSV_PAYLOAD_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Report malformed json bodies in the same shape as our other errors
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(|err, _req| AppError::bad_request(err).into())
+}
+
+/// Report path parameters of the wrong type, such as a non-integer id, as json errors
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|err, _req| AppError::bad_request(err).into())
+}
+
+/// Report query strings that cannot be parsed as json errors
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, _req| AppError::bad_request(err).into())
+}
+
+/// Fallback for requests that match none of the routes
+pub async fn not_found() -> Result<HttpResponse, AppError> {
+    Err(AppError {
+        message: None,
+        cause: None,
+        error_type: AppErrorType::NotFoundError,
+        code: Some("route_not_found"),
+    })
+}
+
+/// Header naming the person making a review decision
+pub const REVIEWER_HEADER: &str = "X-Reviewer";
+
+/// Every `tag` given in the query string, as `tag=a&tag=b` can't be read into a struct
+fn query_tags(req: &HttpRequest) -> Result<Vec<String>, AppError> {
+    let pairs = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map_err(AppError::bad_request)?;
+
+    let mut tags = pairs
+        .iter()
+        .filter(|(key, _)| key == "tag")
+        .map(|(_, tag)| clean_tag(tag))
+        .collect::<Vec<String>>();
+    tags.sort();
+    tags.dedup();
+    Ok(tags)
+}
+
+/// Reviewer recorded in the granule history, taken from the request headers
+fn reviewer(req: &HttpRequest) -> Result<String, AppError> {
+    let reviewer = req
+        .headers()
+        .get(REVIEWER_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| "anonymous".to_string());
+    validation::validate_reviewer(&reviewer)?;
+    Ok(reviewer)
+}
+
 #[get("/")]
 pub async fn status() -> impl Responder {
     HttpResponse::Ok().json(Status {
@@ -43,10 +144,33 @@ pub async fn status() -> impl Responder {
 }
 
 #[get("/exp{_:/?}")]
-pub async fn get_experiments(state: web::Data<AppState>) -> Result<impl Responder, AppError> {
+pub async fn get_experiments(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    filter: web::Query<ExperimentQuery>,
+    page: web::Query<PageQuery>,
+) -> Result<impl Responder, AppError> {
     let log = state.log.new(o!("handler" => "get_experiments"));
+    let mut filter = filter.into_inner();
+    filter.tags = query_tags(&req)?;
+    filter.validate()?;
+    let client = get_client(state.pool.clone(), log.clone()).await?;
+    let result = db::get_experiments(&client, &filter, &page).await;
+
+    page_or_err(&req, result, log)
+}
+
+#[get("/exp/{experiment_id:\\d+}{_:/?}")]
+pub async fn get_experiment(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "get_experiment"));
     let client = get_client(state.pool.clone(), log.clone()).await?;
-    let result = db::get_experiments(&client).await;
+
+    // Unpack the variables from the path/url
+    let web::Path((experiment_id,)) = path;
+    let result = db::get_experiment(&client, experiment_id).await;
 
     json_or_err(result, log)
 }
@@ -54,14 +178,32 @@ pub async fn get_experiments(state: web::Data<AppState>) -> Result<impl Responde
 #[get("/exp/{experiment_id}/granules")]
 pub async fn get_granules(
     state: web::Data<AppState>,
+    req: HttpRequest,
     path: web::Path<(i32,)>,
+    filter: web::Query<GranuleQuery>,
+    page: web::Query<PageQuery>,
 ) -> Result<impl Responder, AppError> {
     let log = state.log.new(o!("handler" => "get_granules"));
+    filter.validate()?;
     let client = get_client(state.pool.clone(), log.clone()).await?;
 
     // Unpack the experiment_Name variable
     let web::Path((experiment_name,)) = path;
-    let result = db::get_granules(&client, experiment_name).await;
+    let result = db::get_granules(&client, experiment_name, &filter, &page).await;
+
+    page_or_err(&req, result, log)
+}
+
+#[get("/exp/{experiment_id}/granules/stats{_:/?}")]
+pub async fn get_granule_stats(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "get_granule_stats"));
+    let client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((experiment_id,)) = path;
+    let result = db::get_granule_stats(&client, experiment_id).await;
 
     json_or_err(result, log)
 }
@@ -69,18 +211,151 @@ pub async fn get_granules(
 #[put("/exp/{experiment_id}/granules/{granule_id}{_:/?}")]
 pub async fn mark_granule_valid(
     state: web::Data<AppState>,
+    req: HttpRequest,
     path: web::Path<(i32, i32)>,
 ) -> Result<impl Responder, AppError> {
     let log = state.log.new(o!("handler" => "mark_granule_valid"));
-    let client = get_client(state.pool.clone(), log.clone()).await?;
+    let reviewer = reviewer(&req)?;
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
 
     // Unpack the variables from the path/url
     let web::Path((experiment_id, granule_id)) = path;
-    let result = db::mark_granule_valid(&client, experiment_id, granule_id).await;
+    let result = db::mark_granule_valid(&mut client, experiment_id, granule_id, &reviewer).await;
 
     result.map(|updated: bool| HttpResponse::Ok().json(ResultResponse { success: updated }))
 }
 
+#[post("/exp/{experiment_id}/granules/{granule_id}/accept{_:/?}")]
+pub async fn accept_granule(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<(i32, i32)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "accept_granule"));
+    let reviewer = reviewer(&req)?;
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((experiment_id, granule_id)) = path;
+    let result = db::set_granule_status(
+        &mut client,
+        experiment_id,
+        granule_id,
+        ReviewStatus::Accepted,
+        None,
+        &reviewer,
+    )
+    .await;
+    json_or_err(result, log)
+}
+
+#[post("/exp/{experiment_id}/granules/{granule_id}/reject{_:/?}")]
+pub async fn reject_granule(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<(i32, i32)>,
+    body: web::Bytes,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "reject_granule"));
+
+    // The reason is optional, so an empty body is allowed, but anything else has to be valid
+    let RejectGranule { reason } = if body.iter().all(u8::is_ascii_whitespace) {
+        RejectGranule::default()
+    } else {
+        serde_json::from_slice(&body).map_err(AppError::bad_request)?
+    };
+    let reviewer = reviewer(&req)?;
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((experiment_id, granule_id)) = path;
+    let result = db::set_granule_status(
+        &mut client,
+        experiment_id,
+        granule_id,
+        ReviewStatus::Rejected,
+        reason,
+        &reviewer,
+    )
+    .await;
+    json_or_err(result, log)
+}
+
+#[post("/exp/{experiment_id}/granules/{granule_id}/reset{_:/?}")]
+pub async fn reset_granule(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<(i32, i32)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "reset_granule"));
+    let reviewer = reviewer(&req)?;
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((experiment_id, granule_id)) = path;
+    let result = db::set_granule_status(
+        &mut client,
+        experiment_id,
+        granule_id,
+        ReviewStatus::Unreviewed,
+        None,
+        &reviewer,
+    )
+    .await;
+    json_or_err(result, log)
+}
+
+#[get("/exp/{experiment_id}/granules/{granule_id}/history{_:/?}")]
+pub async fn get_granule_history(
+    state: web::Data<AppState>,
+    path: web::Path<(i32, i32)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "get_granule_history"));
+    let client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((experiment_id, granule_id)) = path;
+    let result = db::get_granule_history(&client, experiment_id, granule_id).await;
+    json_or_err(result, log)
+}
+
+#[put("/exp/{experiment_id:\\d+}/tags/{tag}{_:/?}")]
+pub async fn add_experiment_tag(
+    state: web::Data<AppState>,
+    path: web::Path<(i32, String)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "add_experiment_tag"));
+    let web::Path((experiment_id, tag)) = path;
+    let tag = clean_tag(&tag);
+    validation::validate_tag(&tag)?;
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let result = db::add_experiment_tag(&mut client, experiment_id, &tag).await;
+    json_or_err(result, log)
+}
+
+#[delete("/exp/{experiment_id:\\d+}/tags/{tag}{_:/?}")]
+pub async fn remove_experiment_tag(
+    state: web::Data<AppState>,
+    path: web::Path<(i32, String)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "remove_experiment_tag"));
+    let client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((experiment_id, tag)) = path;
+    let result = db::remove_experiment_tag(&client, experiment_id, &clean_tag(&tag)).await;
+    json_or_err(result, log)
+}
+
+#[get("/exp/search{_:/?}")]
+pub async fn search_experiments(
+    state: web::Data<AppState>,
+    query: web::Query<SearchQuery>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "search_experiments"));
+    query.validate()?;
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let result = db::search_experiments(&mut client, query.q.trim(), query.limit()).await;
+    json_or_err(result, log)
+}
+
 #[get("/exp/author/{author_name}{_:/?}")]
 pub async fn get_experiment_by_author(
     state: web::Data<AppState>,
@@ -95,16 +370,84 @@ pub async fn get_experiment_by_author(
     json_or_err(result, log)
 }
 
+#[get("/authors{_:/?}")]
+pub async fn get_authors(state: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "get_authors"));
+    let client = get_client(state.pool.clone(), log.clone()).await?;
+    let result = db::get_authors(&client).await;
+    json_or_err(result, log)
+}
+
+#[patch("/authors/{author_id:\\d+}{_:/?}")]
+pub async fn rename_author(
+    state: web::Data<AppState>,
+    json: web::Json<RenameAuthor>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "rename_author"));
+    json.validate()?;
+    let client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((author_id,)) = path;
+    let result = db::rename_author(&client, author_id, &json.name).await;
+    json_or_err(result, log)
+}
+
+#[post("/authors/{author_id:\\d+}/merge{_:/?}")]
+pub async fn merge_authors(
+    state: web::Data<AppState>,
+    json: web::Json<MergeAuthors>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "merge_authors"));
+    json.validate()?;
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((author_id,)) = path;
+    let result = db::merge_authors(&mut client, author_id, &json.from).await;
+    json_or_err(result, log)
+}
+
 #[post("/exp{_:/?}")]
 pub async fn add_experiment(
     state: web::Data<AppState>,
     json: web::Json<CreateExperiment>,
 ) -> Result<impl Responder, AppError> {
     let log = state.log.new(o!("handler" => "add_experiment"));
-    let client = get_client(state.pool.clone(), log.clone()).await?;
+    json.validate()?;
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let result = db::create_experiment(&mut client, &json).await;
+    json_or_err(result, log)
+}
 
-    let CreateExperiment { title, author } = json.into_inner();
-    let result = db::create_experiment(&client, title, author).await;
+#[patch("/exp/{experiment_id:\\d+}{_:/?}")]
+pub async fn update_experiment(
+    state: web::Data<AppState>,
+    json: web::Json<UpdateExperiment>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "update_experiment"));
+    json.validate()?;
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((experiment_id,)) = path;
+    let result = db::update_experiment(&mut client, experiment_id, &json).await;
+    json_or_err(result, log)
+}
+
+#[delete("/exp/{experiment_id:\\d+}{_:/?}")]
+pub async fn delete_experiment(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    query: web::Query<DeleteExperimentQuery>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "delete_experiment"));
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((experiment_id,)) = path;
+    let DeleteExperimentQuery { dry_run } = query.into_inner();
+    let result = db::delete_experiment(&mut client, experiment_id, dry_run).await;
     json_or_err(result, log)
 }
 
@@ -115,6 +458,7 @@ pub async fn add_granule(
     path: web::Path<i32>,
 ) -> Result<impl Responder, AppError> {
     let log = state.log.new(o!("handler" => "add_granule"));
+    json.validate()?;
     let client = get_client(state.pool.clone(), log.clone()).await?;
 
     let web::Path(experiment_id) = path;
@@ -122,3 +466,151 @@ pub async fn add_granule(
     let result = db::create_granule(&client, granule_cmd, experiment_id).await;
     json_or_err(result, log)
 }
+
+#[post("/exp/{experiment_id}/granules/batch{_:/?}")]
+pub async fn add_granules(
+    state: web::Data<AppState>,
+    json: web::Json<Vec<CreateGranule>>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "add_granules"));
+
+    // Check every granule before touching the database, so all the problems are reported at once
+    let granules = json.into_inner();
+    granules.validate()?;
+
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+    let web::Path((experiment_id,)) = path;
+    db::create_granules(&mut client, &granules, experiment_id)
+        .await
+        .map(|created| HttpResponse::Ok().json(BatchGranuleResponse { created }))
+        .map_err(log_error(log))
+}
+
+#[post("/exp/{experiment_id}/granules/import{_:/?}")]
+pub async fn import_granules(
+    state: web::Data<AppState>,
+    body: web::Bytes,
+    path: web::Path<(i32,)>,
+    mapping: web::Query<CsvImportQuery>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "import_granules"));
+    if !mapping.delimiter.is_ascii() {
+        return Err(AppError::bad_request(
+            "The delimiter must be a single ASCII character",
+        ));
+    }
+
+    // Without a usable header nothing can be imported, the problem is keyed by its line
+    let parsed = tabular::parse_granules(&body, &mapping).map_err(|err| {
+        let mut fields = FieldErrors::new();
+        fields.insert(err.line.to_string(), err.errors);
+        AppError::validation_error(fields)
+    })?;
+
+    let mut client = get_client(state.pool.clone(), log.clone()).await?;
+    let web::Path((experiment_id,)) = path;
+    db::create_granules(&mut client, &parsed.granules, experiment_id)
+        .await
+        .map(|created| {
+            HttpResponse::Ok().json(CsvImportResponse {
+                created,
+                rejected: parsed.errors,
+            })
+        })
+        .map_err(log_error(log))
+}
+
+/// Number of granules fetched from the database for each chunk of an export
+const EXPORT_CHUNK_SIZE: i64 = 1000;
+
+/// Progress through the granules of an export
+struct ExportState {
+    client: Client,
+    experiment_id: i32,
+    filter: GranuleQuery,
+    after: Option<i32>,
+    delimiter: u8,
+}
+
+#[get("/exp/{experiment_id}/granules.{format:csv|tsv}")]
+pub async fn export_granules(
+    state: web::Data<AppState>,
+    path: web::Path<(i32, String)>,
+    filter: web::Query<GranuleQuery>,
+    export: web::Query<ExportQuery>,
+) -> Result<impl Responder, AppError> {
+    let log = state.log.new(o!("handler" => "export_granules"));
+    filter.validate()?;
+    let client = get_client(state.pool.clone(), log.clone()).await?;
+
+    let web::Path((experiment_id, format)) = path;
+    let (delimiter, content_type) = match format.as_str() {
+        "tsv" => (b'\t', "text/tab-separated-values"),
+        _ => (b',', "text/csv"),
+    };
+
+    // Check the experiment exists before we start streaming
+    let experiment = db::get_experiment(&client, experiment_id)
+        .await
+        .map_err(log_error(log.clone()))?;
+    let mut head = if export.metadata {
+        tabular::metadata_lines(&experiment).into_bytes()
+    } else {
+        Vec::new()
+    };
+    head.extend(tabular::write_granules(&[], delimiter, true).map_err(AppError::db_error)?);
+
+    // Fetch the granules a chunk at a time, so large experiments are never held in memory
+    let export_state = ExportState {
+        client,
+        experiment_id,
+        filter: filter.into_inner(),
+        after: None,
+        delimiter,
+    };
+    let rows = stream::unfold(Some(export_state), move |export_state| {
+        let log = log.clone();
+        async move {
+            let mut export_state = export_state?;
+            let page = PageQuery {
+                limit: Some(EXPORT_CHUNK_SIZE),
+                after: export_state.after,
+                include_total: false,
+            };
+            let chunk = db::get_granules(
+                &export_state.client,
+                export_state.experiment_id,
+                &export_state.filter,
+                &page,
+            )
+            .await
+            .and_then(|page| {
+                export_state.after = page.next_cursor;
+                tabular::write_granules(&page.items, export_state.delimiter, false)
+                    .map_err(AppError::db_error)
+            })
+            .map(Bytes::from)
+            .map_err(log_error(log));
+
+            let next = match (&chunk, export_state.after) {
+                (Ok(_), Some(_)) => Some(export_state),
+                _ => None,
+            };
+            Some((chunk, next))
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"experiment-{}.{}\"",
+                experiment_id, format
+            ),
+        )
+        .streaming(
+            stream::once(future::ok::<_, AppError>(Bytes::from(head))).chain(Box::pin(rows)),
+        ))
+}
@@ -4,19 +4,41 @@ use models::AppState;
 use serde_json;
 use tera::Tera;
 
+use actix_http::Request;
+use actix_web::dev::{MessageBody, Service, ServiceResponse};
 use lazy_static::lazy_static;
 
 lazy_static! {
     static ref APP_STATE: AppState = {
         dotenv().ok();
-        let config = Config::from_env().unwrap();
-        let log = config.configure_log();
-        let pool = config.configure_pool();
+        let layers = Config::layers(&config::Args::default()).unwrap();
+        let config = Config::from_layers(&layers).unwrap();
+        let log = config.configure_log().unwrap();
+        let pool = config.configure_pool().unwrap();
         let tera = config.configure_tera();
         models::AppState { pool, log, tera }
     };
 }
 
+/// Create an experiment by Test Author, as most tests need one to work with
+async fn create_experiment<S, B>(app: &mut S, title: &str) -> models::Experiment
+where
+    S: Service<Request = Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody + Unpin,
+{
+    let new_experiment = models::CreateExperiment {
+        title: title.to_string(),
+        author: "Test Author".to_string(),
+        ..Default::default()
+    };
+    let req = test::TestRequest::post()
+        .uri("/exp/")
+        .header("Content-Type", "application/json")
+        .set_payload(serde_json::to_string(&new_experiment).unwrap())
+        .to_request();
+    test::read_response_json(app, req).await
+}
+
 #[actix_rt::test]
 async fn test_experiment_response() {
     let app = App::new()
@@ -31,7 +53,7 @@ async fn test_experiment_response() {
 
     let body = test::read_body(response).await;
 
-    let try_experiments: Result<Vec<models::Experiment>, serde_json::error::Error> =
+    let try_experiments: Result<models::Page<models::Experiment>, serde_json::error::Error> =
         serde_json::from_slice(&body);
 
     assert!(try_experiments.is_ok(), "Response could not be parsed");
@@ -49,7 +71,7 @@ async fn test_response() {
     // Create a request to create a new experiment
     let title = "New Experiment".to_string();
     let author = "Test Author".to_string();
-    let new_experiment = models::CreateExperiment { title, author };
+    let new_experiment = models::CreateExperiment { title, author, ..Default::default() };
     let new_experiment_expected = serde_json::to_string(&new_experiment).unwrap();
 
     // Send this new request
@@ -72,8 +94,9 @@ async fn test_response() {
 
     // Does this experiment exist in the table
     let req = test::TestRequest::get().uri("/exp/").to_request();
-    let experiments: Vec<models::Experiment> = test::read_response_json(&mut app, req).await;
+    let experiments: models::Page<models::Experiment> = test::read_response_json(&mut app, req).await;
     let try_experiment = experiments
+        .items
         .iter()
         .find(|experiment| experiment.id == new_experiment.id);
 
@@ -93,22 +116,11 @@ async fn test_granule_add() {
     let mut app = test::init_service(app).await;
 
     // First we have to create a new experiment
-    let title = "New Experiment".to_string();
-    let author = "Test Author".to_string();
-    let new_experiment = models::CreateExperiment { title, author };
-    let new_experiment_json = serde_json::to_string(&new_experiment).unwrap();
-    let req = test::TestRequest::post()
-        .uri("/exp/")
-        .header("Content-Type", "application/json")
-        .set_payload(new_experiment_json.clone())
-        .to_request();
-    let response = test::call_service(&mut app, req).await;
-    let body = test::read_body(response).await;
-    let new_experiment = serde_json::from_slice::<models::Experiment>(&body).expect("Unable to create experiment in create granule test");
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
     let experiment_id = new_experiment.id;
 
     // Use this to create a granule
-    let new_granule = models::CreateGranule {valid:false, area:1.0};
+    let new_granule = models::CreateGranule {valid:false, area:Some(1.0)};
     let new_granule_json = serde_json::to_string(&new_granule).unwrap();
     let uri = format!("/exp/{}/granules", experiment_id);
     let req = test::TestRequest::post()
@@ -135,8 +147,8 @@ async fn test_granule_add() {
     assert_eq!(response.status(), 200, "GET {} should return 200", uri);
     let body = test::read_body(response).await;
 
-    let granules: Vec<models::Granule> = serde_json::from_slice(&body).expect("Unable to parse granule list");
-    let try_granule = granules.iter().find(|granule| granule.id == granule.id);
+    let granules: models::Page<models::Granule> = serde_json::from_slice(&body).expect("Unable to parse granule list");
+    let try_granule = granules.items.iter().find(|granule| granule.id == granule.id);
     assert!(
         try_granule.is_some(),
         "Unable to find created granule"
@@ -155,22 +167,11 @@ async fn test_granule_mark() {
     let mut app = test::init_service(app).await;
 
     // First we have to create a new experiment
-    let title = "New Experiment".to_string();
-    let author = "Test Author".to_string();
-    let new_experiment = models::CreateExperiment { title, author };
-    let new_experiment_json = serde_json::to_string(&new_experiment).unwrap();
-    let req = test::TestRequest::post()
-        .uri("/exp/")
-        .header("Content-Type", "application/json")
-        .set_payload(new_experiment_json.clone())
-        .to_request();
-    let response = test::call_service(&mut app, req).await;
-    let body = test::read_body(response).await;
-    let new_experiment = serde_json::from_slice::<models::Experiment>(&body).expect("Unable to create experiment in create granule test");
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
     let experiment_id = new_experiment.id;
 
     // Use this to create a granule
-    let new_granule = models::CreateGranule {valid:false, area:1.0};
+    let new_granule = models::CreateGranule {valid:false, area:Some(1.0)};
     let new_granule_json = serde_json::to_string(&new_granule).unwrap();
     let uri = format!("/exp/{}/granules", experiment_id);
     let req = test::TestRequest::post()
@@ -209,3 +210,1194 @@ async fn test_granule_mark() {
 
     assert!(!success, "Second marking of granule should fail");
 }
+
+#[actix_rt::test]
+async fn test_experiment_get() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::get_experiment);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
+
+    // Can we fetch it back by id?
+    let uri = format!("/exp/{}", new_experiment.id);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "GET {} should return 200", uri);
+    let body = test::read_body(response).await;
+    let experiment: models::Experiment = serde_json::from_slice(&body).expect("Unable to parse experiment");
+    assert_eq!(experiment.id, new_experiment.id, "Wrong experiment returned");
+
+    // A missing experiment should be a 404 rather than an empty response
+    let uri = format!("/exp/{}", i32::MAX);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "GET {} should return 404", uri);
+}
+
+#[actix_rt::test]
+async fn test_experiment_update() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::update_experiment);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
+
+    // Only change the title, the author should be left alone
+    let update = models::UpdateExperiment { title: Some("Renamed Experiment".to_string()), ..Default::default() };
+    let update_json = serde_json::to_string(&update).unwrap();
+    let uri = format!("/exp/{}", new_experiment.id);
+    let req = test::TestRequest::patch()
+        .uri(&uri)
+        .header("Content-Type", "application/json")
+        .set_payload(update_json.clone())
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "PATCH {} should return 200", uri);
+    let body = test::read_body(response).await;
+    let experiment: models::Experiment = serde_json::from_slice(&body).expect("Unable to parse experiment");
+    assert_eq!(experiment.title, "Renamed Experiment", "Title should be updated");
+    assert_eq!(experiment.author, "Test Author", "Author should be unchanged");
+    assert!(experiment.updated_at > new_experiment.updated_at, "The update time should move forward");
+
+    // Optional fields can be set and then cleared again with an explicit null
+    let details = r#"{"description": "Arsenite dose series", "acquired_on": "2021-03-01", "conditions": {"stressor": "arsenite"}}"#;
+    let req = test::TestRequest::patch().uri(&uri).header("Content-Type", "application/json").set_payload(details).to_request();
+    let experiment: models::Experiment = test::read_response_json(&mut app, req).await;
+    assert_eq!(experiment.description.as_deref(), Some("Arsenite dose series"));
+    assert_eq!(experiment.acquired_on, Some(chrono::NaiveDate::from_ymd(2021, 3, 1)));
+
+    let req = test::TestRequest::patch()
+        .uri(&uri)
+        .header("Content-Type", "application/json")
+        .set_payload(r#"{"description": null, "conditions": null}"#)
+        .to_request();
+    let experiment: models::Experiment = test::read_response_json(&mut app, req).await;
+    assert_eq!(experiment.description, None, "An explicit null should clear the description");
+    assert_eq!(experiment.conditions, serde_json::json!({}), "An explicit null should clear the conditions");
+    assert_eq!(experiment.acquired_on, Some(chrono::NaiveDate::from_ymd(2021, 3, 1)), "A missing field should be left alone");
+
+    // Updating a missing experiment should be a 404
+    let uri = format!("/exp/{}", i32::MAX);
+    let req = test::TestRequest::patch()
+        .uri(&uri)
+        .header("Content-Type", "application/json")
+        .set_payload(update_json)
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "PATCH {} should return 404", uri);
+}
+
+#[actix_rt::test]
+async fn test_experiment_delete() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::get_experiment)
+        .service(handler::delete_experiment)
+        .service(handler::add_granule);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
+    let experiment_id = new_experiment.id;
+
+    // Give it a couple of granules
+    let granule_uri = format!("/exp/{}/granules", experiment_id);
+    for _ in 0..2 {
+        let new_granule = models::CreateGranule {valid:false, area:Some(1.0)};
+        let new_granule_json = serde_json::to_string(&new_granule).unwrap();
+        let req = test::TestRequest::post()
+            .uri(&granule_uri)
+            .header("Content-Type", "application/json")
+            .set_payload(new_granule_json)
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), 200, "POST {} should return 200", granule_uri);
+    }
+
+    // A dry run reports the granules but leaves the experiment in place
+    let uri = format!("/exp/{}", experiment_id);
+    let dry_run_uri = format!("/exp/{}?dry_run=true", experiment_id);
+    let req = test::TestRequest::delete().uri(&dry_run_uri).to_request();
+    let response: models::DeleteExperimentResponse = test::read_response_json(&mut app, req).await;
+    assert_eq!(response.granules_deleted, 2, "Dry run should count both granules");
+    assert!(response.dry_run, "Response should be marked as a dry run");
+
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "Experiment should survive a dry run");
+
+    // The real delete removes everything
+    let req = test::TestRequest::delete().uri(&uri).to_request();
+    let response: models::DeleteExperimentResponse = test::read_response_json(&mut app, req).await;
+    assert_eq!(response.granules_deleted, 2, "Delete should remove both granules");
+    assert!(!response.dry_run, "Response should not be marked as a dry run");
+
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "GET {} should return 404 after delete", uri);
+
+    // Deleting again should be a 404
+    let req = test::TestRequest::delete().uri(&uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "DELETE {} should return 404", uri);
+}
+
+#[actix_rt::test]
+async fn test_granule_pagination() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::add_granule)
+        .service(handler::get_granules);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
+    let experiment_id = new_experiment.id;
+
+    // Add three granules so they span two pages
+    let uri = format!("/exp/{}/granules", experiment_id);
+    for _ in 0..3 {
+        let new_granule = models::CreateGranule {valid:false, area:Some(1.0)};
+        let new_granule_json = serde_json::to_string(&new_granule).unwrap();
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .header("Content-Type", "application/json")
+            .set_payload(new_granule_json)
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), 200, "POST {} should return 200", uri);
+    }
+
+    // The first page should point at the second
+    let first_uri = format!("{}?limit=2&include_total=true", uri);
+    let req = test::TestRequest::get().uri(&first_uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "GET {} should return 200", first_uri);
+    let link = response
+        .headers()
+        .get("Link")
+        .expect("First page should have a Link header")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body = test::read_body(response).await;
+    let first_page: models::Page<models::Granule> = serde_json::from_slice(&body).expect("Unable to parse granule page");
+    assert_eq!(first_page.items.len(), 2, "First page should be full");
+    assert_eq!(first_page.total, Some(3), "Total should count every granule");
+    let cursor = first_page.next_cursor.expect("First page should have a cursor");
+    assert!(link.contains(&format!("after={}", cursor)), "Link header should contain the cursor");
+
+    // The second page holds the remainder and has no cursor
+    let second_uri = format!("{}?limit=2&after={}", uri, cursor);
+    let req = test::TestRequest::get().uri(&second_uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert!(response.headers().get("Link").is_none(), "Last page should not have a Link header");
+    let body = test::read_body(response).await;
+    let second_page: models::Page<models::Granule> = serde_json::from_slice(&body).expect("Unable to parse granule page");
+    assert_eq!(second_page.items.len(), 1, "Second page should hold the last granule");
+    assert_eq!(second_page.next_cursor, None, "Last page should have no cursor");
+    assert_eq!(second_page.total, None, "Total should only be sent when asked for");
+}
+
+#[actix_rt::test]
+async fn test_granule_filter() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::add_granule)
+        .service(handler::get_granules);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
+    let experiment_id = new_experiment.id;
+
+    // Add a mix of granules
+    let uri = format!("/exp/{}/granules", experiment_id);
+    let granules = vec![(true, 1.0), (true, 5.0), (false, 4.0), (true, 3.0)];
+    for (valid, area) in granules {
+        let new_granule = models::CreateGranule {valid, area: Some(area)};
+        let new_granule_json = serde_json::to_string(&new_granule).unwrap();
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .header("Content-Type", "application/json")
+            .set_payload(new_granule_json)
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), 200, "POST {} should return 200", uri);
+    }
+
+    // Large valid granules, largest first, one per page
+    let mut areas = Vec::new();
+    let mut filter_uri = format!("{}?valid=true&min_area=2&sort=-area&limit=1", uri);
+    loop {
+        let req = test::TestRequest::get().uri(&filter_uri).to_request();
+        let page: models::Page<models::Granule> = test::read_response_json(&mut app, req).await;
+        areas.extend(page.items.iter().map(|granule| granule.area));
+        match page.next_cursor {
+            Some(cursor) => filter_uri = format!("{}?valid=true&min_area=2&sort=-area&limit=1&after={}", uri, cursor),
+            None => break,
+        }
+    }
+    assert_eq!(areas, vec![Some(5.0), Some(3.0)], "Filtered granules should be sorted by descending area");
+
+    // Unknown sort orders are rejected
+    let req = test::TestRequest::get().uri(&format!("{}?sort=colour", uri)).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 400, "Unknown sort should return 400");
+
+    // Area cursors have to be granules of the experiment being listed
+    let req = test::TestRequest::get().uri(&format!("{}?sort=area&after={}", uri, i32::MAX)).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 400, "A cursor from elsewhere should return 400");
+}
+
+#[actix_rt::test]
+async fn test_missing_area() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::add_granules)
+        .service(handler::export_granules)
+        .service(handler::get_granules)
+        .service(handler::get_granule_stats);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "Missing Area Experiment").await;
+    let experiment_id = new_experiment.id;
+
+    // Granules clipped at the edge have no area, which can be sent as null or left out
+    let batch_uri = format!("/exp/{}/granules/batch", experiment_id);
+    let req = test::TestRequest::post()
+        .uri(&batch_uri)
+        .header("Content-Type", "application/json")
+        .set_payload(r#"[{"valid": false, "area": 2.0}, {"valid": false, "area": null}, {"valid": false, "area": 1.0}, {"valid": false}]"#)
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "POST {} should return 200", batch_uri);
+
+    // Missing areas come last whichever way the granules are sorted
+    let uri = format!("/exp/{}/granules", experiment_id);
+    for (sort, expected) in [
+        ("area", vec![Some(1.0), Some(2.0), None, None]),
+        ("-area", vec![Some(2.0), Some(1.0), None, None]),
+    ] {
+        let mut areas = Vec::new();
+        let mut page_uri = format!("{}?sort={}&limit=1", uri, sort);
+        loop {
+            let req = test::TestRequest::get().uri(&page_uri).to_request();
+            let page: models::Page<models::Granule> = test::read_response_json(&mut app, req).await;
+            areas.extend(page.items.iter().map(|granule| granule.area));
+            match page.next_cursor {
+                Some(cursor) => page_uri = format!("{}?sort={}&limit=1&after={}", uri, sort, cursor),
+                None => break,
+            }
+        }
+        assert_eq!(areas, expected, "Granules sorted by {} should page through missing areas", sort);
+    }
+
+    let req = test::TestRequest::get().uri(&format!("{}?has_area=false&include_total=true", uri)).to_request();
+    let page: models::Page<models::Granule> = test::read_response_json(&mut app, req).await;
+    assert_eq!(page.total, Some(2), "Only granules without an area should be listed");
+
+    // Statistics count the missing areas separately
+    let stats_uri = format!("/exp/{}/granules/stats", experiment_id);
+    let req = test::TestRequest::get().uri(&stats_uri).to_request();
+    let stats: models::GranuleStats = test::read_response_json(&mut app, req).await;
+    assert_eq!(stats.count, 4);
+    assert_eq!(stats.with_area, 2);
+    assert_eq!(stats.missing_area, 2);
+    assert_eq!(stats.min_area, Some(1.0));
+    assert_eq!(stats.max_area, Some(2.0));
+    assert_eq!(stats.mean_area, Some(1.5), "The mean should ignore missing areas");
+
+    let stats_uri = format!("/exp/{}/granules/stats", i32::MAX);
+    let req = test::TestRequest::get().uri(&stats_uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "GET {} should return 404", stats_uri);
+
+    // Exports leave the cell empty
+    let export_uri = format!("/exp/{}/granules.csv", experiment_id);
+    let req = test::TestRequest::get().uri(&export_uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    let body = test::read_body(response).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    let empty_areas = body.lines().skip(1).filter(|line| line.split(',').nth(2) == Some("")).count();
+    assert_eq!(empty_areas, 2, "Missing areas should be exported as empty cells");
+}
+
+#[actix_rt::test]
+async fn test_experiment_metadata() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::get_experiments);
+    let mut app = test::init_service(app).await;
+
+    // Tag both experiments with a run id, so other tests' experiments don't get in the way
+    let run = uuid::Uuid::new_v4().to_string();
+    let experiments = vec![
+        ("2021-03-01", serde_json::json!({"run": run, "cell_line": "HeLa", "stressor": "arsenite", "dose_um": 500})),
+        ("2021-04-10", serde_json::json!({"run": run, "cell_line": "U2OS"})),
+    ];
+    for (acquired_on, conditions) in experiments {
+        let new_experiment = models::CreateExperiment {
+            title: "Metadata Experiment".to_string(),
+            author: "Test Author".to_string(),
+            description: Some("Stress granules under arsenite".to_string()),
+            acquired_on: Some(acquired_on.parse().unwrap()),
+            conditions: Some(conditions.clone()),
+        };
+        let new_experiment_json = serde_json::to_string(&new_experiment).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/exp/")
+            .header("Content-Type", "application/json")
+            .set_payload(new_experiment_json)
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), 200, "POST /exp/ should return 200");
+        let body = test::read_body(response).await;
+        let experiment: models::Experiment = serde_json::from_slice(&body).expect("Unable to parse experiment");
+        assert_eq!(experiment.conditions, conditions, "Conditions should be stored");
+        assert_eq!(experiment.acquired_on.unwrap().to_string(), acquired_on, "The acquisition date should be stored");
+        assert_eq!(experiment.created_at, experiment.updated_at, "New experiments have not been updated");
+    }
+
+    // Filter on the run, then by date and condition keys
+    let run_filter = format!("conditions=%7B%22run%22%3A%22{}%22%7D", run);
+    let filters = vec![
+        ("".to_string(), 2),
+        ("&acquired_from=2021-04-01".to_string(), 1),
+        ("&acquired_from=2021-03-01&acquired_to=2021-03-01".to_string(), 1),
+        ("&condition_keys=stressor,dose_um".to_string(), 1),
+        ("&condition_keys=passage".to_string(), 0),
+    ];
+    for (filter, expected) in filters {
+        let uri = format!("/exp?{}{}", run_filter, filter);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let page: models::Page<models::Experiment> = test::read_response_json(&mut app, req).await;
+        assert_eq!(page.items.len(), expected, "GET {} should find {} experiments", uri, expected);
+    }
+
+    // Malformed filters are rejected
+    for uri in [
+        "/exp?conditions=%5B1%5D",
+        "/exp?acquired_from=2021-04-01&acquired_to=2021-03-01",
+    ] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), 422, "GET {} should return 422", uri);
+    }
+}
+
+#[actix_rt::test]
+async fn test_authors() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::get_experiment)
+        .service(handler::get_experiment_by_author)
+        .service(handler::get_authors)
+        .service(handler::rename_author)
+        .service(handler::merge_authors);
+    let mut app = test::init_service(app).await;
+
+    // Spellings that only differ by case and spacing share an author
+    let suffix = uuid::Uuid::new_v4().to_simple().to_string();
+    let names = vec![
+        format!("Jane Smith {}", suffix),
+        format!("  jane   smith {} ", suffix),
+        format!("J. Smith {}", suffix),
+    ];
+    let mut experiments = Vec::new();
+    for author in names {
+        let new_experiment = models::CreateExperiment { title: "Author Experiment".to_string(), author, ..Default::default() };
+        let new_experiment_json = serde_json::to_string(&new_experiment).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/exp/")
+            .header("Content-Type", "application/json")
+            .set_payload(new_experiment_json)
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        let body = test::read_body(response).await;
+        experiments.push(serde_json::from_slice::<models::Experiment>(&body).expect("Unable to create experiment in author test"));
+    }
+    assert_eq!(experiments[0].author_id, experiments[1].author_id, "Differently spaced names should be the same author");
+    assert_eq!(experiments[1].author, format!("Jane Smith {}", suffix), "The first spelling should be kept");
+    assert_ne!(experiments[0].author_id, experiments[2].author_id, "Different names should be different authors");
+
+    let req = test::TestRequest::get().uri("/authors").to_request();
+    let authors: Vec<models::AuthorSummary> = test::read_response_json(&mut app, req).await;
+    let jane = authors.iter().find(|author| author.id == experiments[0].author_id).expect("The author should be listed");
+    assert_eq!(jane.experiment_count, 2, "Both experiments should be counted");
+    assert_eq!(jane.granule_count, 0);
+
+    // Looking up an author's experiments ignores spacing the same way
+    let uri = format!("/exp/author/%20jane%20%20smith%20{}", suffix);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let found: Vec<models::Experiment> = test::read_response_json(&mut app, req).await;
+    assert_eq!(found.len(), 2, "GET {} should find both of the author's experiments", uri);
+
+    // Renaming changes the author of every experiment
+    let jane_id = experiments[0].author_id;
+    let j_id = experiments[2].author_id;
+    let uri = format!("/authors/{}", jane_id);
+    let req = test::TestRequest::patch()
+        .uri(&uri)
+        .header("Content-Type", "application/json")
+        .set_payload(format!(r#"{{"name": "Jane Q. Smith {}"}}"#, suffix))
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "PATCH {} should return 200", uri);
+    let req = test::TestRequest::get().uri(&format!("/exp/{}", experiments[1].id)).to_request();
+    let experiment: models::Experiment = test::read_response_json(&mut app, req).await;
+    assert_eq!(experiment.author, format!("Jane Q. Smith {}", suffix), "Experiments should show the new name");
+
+    // Taking another author's name is a conflict
+    let req = test::TestRequest::patch()
+        .uri(&uri)
+        .header("Content-Type", "application/json")
+        .set_payload(format!(r#"{{"name": "j. smith {}"}}"#, suffix))
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 409, "PATCH {} with a taken name should return 409", uri);
+
+    // Merging moves the experiments and removes the old author
+    let uri = format!("/authors/{}/merge", jane_id);
+    let req = test::TestRequest::post()
+        .uri(&uri)
+        .header("Content-Type", "application/json")
+        .set_payload(format!(r#"{{"from": [{}]}}"#, j_id))
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "POST {} should return 200", uri);
+    let body = test::read_body(response).await;
+    let merge: models::MergeAuthorsResponse = serde_json::from_slice(&body).expect("Unable to parse merge response");
+    assert_eq!(merge.experiments_moved, 1);
+    assert_eq!(merge.merged, vec![j_id]);
+    let req = test::TestRequest::get().uri(&format!("/exp/{}", experiments[2].id)).to_request();
+    let experiment: models::Experiment = test::read_response_json(&mut app, req).await;
+    assert_eq!(experiment.author_id, jane_id, "Experiments should move to the merged author");
+
+    let req = test::TestRequest::get().uri("/authors").to_request();
+    let authors: Vec<models::AuthorSummary> = test::read_response_json(&mut app, req).await;
+    assert!(authors.iter().all(|author| author.id != j_id), "The merged author should be removed");
+
+    // Merging a missing author, or an author into itself, fails
+    for (body, status) in [
+        (format!(r#"{{"from": [{}]}}"#, i32::MAX), 404),
+        (format!(r#"{{"from": [{}]}}"#, jane_id), 400),
+        (r#"{"from": []}"#.to_string(), 422),
+    ] {
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .header("Content-Type", "application/json")
+            .set_payload(body.clone())
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), status, "POST {} with {} should return {}", uri, body, status);
+    }
+}
+
+#[actix_rt::test]
+async fn test_search_experiments() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::search_experiments);
+    let mut app = test::init_service(app).await;
+
+    // Give both experiments words no other test uses
+    let unique = uuid::Uuid::new_v4().to_simple().to_string();
+    let (title_word, author_word) = (&unique[..8], &unique[8..16]);
+    let experiments = vec![
+        (format!("Heat shock granulation {}", title_word), "Test Author".to_string()),
+        ("Arsenite time course".to_string(), format!("Rosalind Franklin{}", author_word)),
+    ];
+    let mut ids = Vec::new();
+    for (title, author) in experiments {
+        let new_experiment = models::CreateExperiment { title, author, ..Default::default() };
+        let new_experiment_json = serde_json::to_string(&new_experiment).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/exp/")
+            .header("Content-Type", "application/json")
+            .set_payload(new_experiment_json)
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        let body = test::read_body(response).await;
+        ids.push(serde_json::from_slice::<models::Experiment>(&body).expect("Unable to create experiment in search test").id);
+    }
+
+    // Title words are stemmed, and author names survive typos
+    let searches = vec![
+        (format!("granulations%20{}", title_word), ids[0]),
+        (format!("Franklen{}", author_word), ids[1]),
+    ];
+    for (q, expected) in searches {
+        let uri = format!("/exp/search?q={}", q);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let matches: Vec<models::ExperimentMatch> = test::read_response_json(&mut app, req).await;
+        assert!(!matches.is_empty(), "GET {} should find an experiment", uri);
+        assert_eq!(matches[0].experiment.id, expected, "GET {} should rank the matching experiment first", uri);
+        assert!(matches.windows(2).all(|pair| pair[0].rank >= pair[1].rank), "Results should be ordered by rank");
+    }
+
+    let req = test::TestRequest::get().uri("/exp/search?q=%20").to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 422, "A blank search should return 422");
+}
+
+#[actix_rt::test]
+async fn test_experiment_tags() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .app_data(handler::query_config())
+        .service(handler::add_experiment)
+        .service(handler::get_experiments)
+        .service(handler::add_experiment_tag)
+        .service(handler::remove_experiment_tag);
+    let mut app = test::init_service(app).await;
+
+    // Tags no other test uses
+    let unique = uuid::Uuid::new_v4().to_simple().to_string();
+    let (arsenite, pilot) = (format!("arsenite-{}", &unique[..8]), format!("pilot-{}", &unique[8..16]));
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let new_experiment = models::CreateExperiment { title: "Tagged Experiment".to_string(), author: "Test Author".to_string(), ..Default::default() };
+        let new_experiment_json = serde_json::to_string(&new_experiment).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/exp/")
+            .header("Content-Type", "application/json")
+            .set_payload(new_experiment_json)
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        let body = test::read_body(response).await;
+        let experiment = serde_json::from_slice::<models::Experiment>(&body).expect("Unable to create experiment in tag test");
+        assert!(experiment.tags.is_empty(), "New experiments have no tags");
+        ids.push(experiment.id);
+    }
+
+    // Both experiments are arsenite, only the first is a pilot. Tags ignore case and adding
+    // one twice does nothing.
+    for (id, tag) in [
+        (ids[0], pilot.to_uppercase()),
+        (ids[0], arsenite.clone()),
+        (ids[0], arsenite.clone()),
+        (ids[1], arsenite.clone()),
+    ] {
+        let uri = format!("/exp/{}/tags/{}", id, tag);
+        let req = test::TestRequest::put().uri(&uri).to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), 200, "PUT {} should return 200", uri);
+    }
+    let uri = format!("/exp/{}/tags/{}", ids[0], pilot);
+    let req = test::TestRequest::put().uri(&uri).to_request();
+    let experiment: models::Experiment = test::read_response_json(&mut app, req).await;
+    assert_eq!(experiment.tags, vec![arsenite.clone(), pilot.clone()], "Tags should be listed in order");
+
+    let filters = vec![
+        (format!("tag={}", arsenite), vec![ids[1], ids[0]]),
+        (format!("tag={}&tag={}", arsenite, pilot), vec![ids[0]]),
+        (format!("tag={}&tag={}&tag_match=all", arsenite, pilot.to_uppercase()), vec![ids[0]]),
+        (format!("tag={}&tag={}&tag_match=any", arsenite, pilot), vec![ids[1], ids[0]]),
+        (format!("tag={}&tag=unused-{}", pilot, unique), vec![]),
+    ];
+    for (filter, expected) in filters {
+        let uri = format!("/exp?{}", filter);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let page: models::Page<models::Experiment> = test::read_response_json(&mut app, req).await;
+        let found = page.items.iter().map(|experiment| experiment.id).collect::<Vec<i32>>();
+        assert_eq!(found, expected, "GET {} should find the tagged experiments", uri);
+    }
+
+    // Removing a tag
+    let uri = format!("/exp/{}/tags/{}", ids[0], pilot);
+    let req = test::TestRequest::delete().uri(&uri).to_request();
+    let experiment: models::Experiment = test::read_response_json(&mut app, req).await;
+    assert_eq!(experiment.tags, vec![arsenite.clone()], "The tag should be removed");
+
+    let failures = vec![
+        (test::TestRequest::delete().uri(&uri).to_request(), 404, "Removing a missing tag"),
+        (test::TestRequest::put().uri(&format!("/exp/{}/tags/{}", i32::MAX, pilot)).to_request(), 404, "Tagging a missing experiment"),
+        (test::TestRequest::put().uri(&format!("/exp/{}/tags/heat%20shock", ids[0])).to_request(), 422, "A tag with a space"),
+        (test::TestRequest::get().uri("/exp?tag=pilot&tag_match=some").to_request(), 400, "An unknown tag match"),
+    ];
+    for (req, status, description) in failures {
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), status, "{} should return {}", description, status);
+    }
+}
+
+#[actix_rt::test]
+async fn test_pg_sslmode() {
+    // Run with PG.SSLMODE=require against a server with TLS to check encrypted connections, see
+    // tls/generate.sh for certificates rustls accepts and docker-compose.tls.yml for the server
+    let client = APP_STATE.pool.get().await.unwrap();
+    let layers = Config::layers(&config::Args::default()).unwrap();
+    let config = Config::from_layers(&layers).unwrap();
+
+    let row = client
+        .query_one(
+            "select coalesce(ssl, false), client_dn from pg_stat_ssl where pid = pg_backend_pid()",
+            &[],
+        )
+        .await
+        .unwrap();
+    let ssl: bool = row.get(0);
+    let client_dn: Option<String> = row.get(1);
+
+    match config.tls.sslmode {
+        config::SslMode::Require => assert!(ssl, "Connections should use TLS"),
+        config::SslMode::Disable => assert!(!ssl, "Connections shouldn't use TLS"),
+        config::SslMode::Prefer => (),
+    }
+    if ssl && config.tls.sslcert.is_some() {
+        assert!(client_dn.is_some(), "The server should have been sent the client certificate");
+    }
+}
+
+#[actix_rt::test]
+async fn test_json_payload_limit() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .app_data(handler::json_config(64))
+        .service(handler::add_experiment);
+    let mut app = test::init_service(app).await;
+
+    // A body over the configured limit is refused before it reaches the handler
+    let new_experiment = models::CreateExperiment {
+        title: "An experiment with a title long enough to go over the limit".to_string(),
+        author: "Payload Limit Author".to_string(),
+        ..Default::default()
+    };
+    let req = test::TestRequest::post()
+        .uri("/exp/")
+        .header("Content-Type", "application/json")
+        .set_payload(serde_json::to_string(&new_experiment).unwrap())
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 400, "A body over the limit should return 400");
+    let body = test::read_body(response).await;
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("Error should be json");
+    assert_eq!(body["code"], "bad_request");
+}
+
+#[actix_rt::test]
+async fn test_granule_review() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::add_granule)
+        .service(handler::accept_granule)
+        .service(handler::reject_granule)
+        .service(handler::reset_granule);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
+    let experiment_id = new_experiment.id;
+
+    // Use this to create a granule
+    let new_granule = models::CreateGranule {valid:false, area:Some(1.0)};
+    let new_granule_json = serde_json::to_string(&new_granule).unwrap();
+    let uri = format!("/exp/{}/granules", experiment_id);
+    let req = test::TestRequest::post()
+        .uri(&uri)
+        .header("Content-Type", "application/json")
+        .set_payload(new_granule_json.clone())
+        .to_request();
+    let granule: models::Granule = test::read_response_json(&mut app, req).await;
+    assert_eq!(granule.status, models::ReviewStatus::Unreviewed, "New granules should be unreviewed");
+
+    // Reject with a reason
+    let reject_uri = format!("/exp/{}/granules/{}/reject", experiment_id, granule.id);
+    let reason = models::RejectGranule { reason: Some("Out of focus".to_string()) };
+    let req = test::TestRequest::post()
+        .uri(&reject_uri)
+        .header("Content-Type", "application/json")
+        .set_payload(serde_json::to_string(&reason).unwrap())
+        .to_request();
+    let granule: models::Granule = test::read_response_json(&mut app, req).await;
+    assert_eq!(granule.status, models::ReviewStatus::Rejected, "Granule should be rejected");
+    assert_eq!(granule.rejection_reason, Some("Out of focus".to_string()), "Reason should be kept");
+    assert!(!granule.valid, "Rejected granules are not valid");
+
+    // Accepting clears the reason
+    let accept_uri = format!("/exp/{}/granules/{}/accept", experiment_id, granule.id);
+    let req = test::TestRequest::post().uri(&accept_uri).to_request();
+    let granule: models::Granule = test::read_response_json(&mut app, req).await;
+    assert_eq!(granule.status, models::ReviewStatus::Accepted, "Granule should be accepted");
+    assert_eq!(granule.rejection_reason, None, "Reason should be cleared");
+    assert!(granule.valid, "Accepted granules are valid");
+
+    // Reset undoes the review
+    let reset_uri = format!("/exp/{}/granules/{}/reset", experiment_id, granule.id);
+    let req = test::TestRequest::post().uri(&reset_uri).to_request();
+    let granule: models::Granule = test::read_response_json(&mut app, req).await;
+    assert_eq!(granule.status, models::ReviewStatus::Unreviewed, "Granule should be unreviewed");
+
+    // A body that isn't a reason is refused rather than ignored
+    let req = test::TestRequest::post()
+        .uri(&reject_uri)
+        .header("Content-Type", "application/json")
+        .set_payload(r#"{"reason": 5}"#)
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 400, "A malformed reason should return 400");
+
+    // No body at all rejects without a reason
+    let req = test::TestRequest::post().uri(&reject_uri).to_request();
+    let granule: models::Granule = test::read_response_json(&mut app, req).await;
+    assert_eq!(granule.status, models::ReviewStatus::Rejected, "Granule should be rejected");
+    assert_eq!(granule.rejection_reason, None, "There should be no reason");
+
+    // Reviewing a missing granule is a 404
+    let missing_uri = format!("/exp/{}/granules/{}/accept", experiment_id, i32::MAX);
+    let req = test::TestRequest::post().uri(&missing_uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "POST {} should return 404", missing_uri);
+}
+
+#[actix_rt::test]
+async fn test_granule_history() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::add_granule)
+        .service(handler::mark_granule_valid)
+        .service(handler::reject_granule)
+        .service(handler::delete_experiment)
+        .service(handler::get_granule_history);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
+    let experiment_id = new_experiment.id;
+
+    // Use this to create a granule
+    let new_granule = models::CreateGranule {valid:false, area:Some(1.0)};
+    let new_granule_json = serde_json::to_string(&new_granule).unwrap();
+    let uri = format!("/exp/{}/granules", experiment_id);
+    let req = test::TestRequest::post()
+        .uri(&uri)
+        .header("Content-Type", "application/json")
+        .set_payload(new_granule_json.clone())
+        .to_request();
+    let granule: models::Granule = test::read_response_json(&mut app, req).await;
+
+    // Mark it valid and then change our mind
+    let mark_uri = format!("/exp/{}/granules/{}", experiment_id, granule.id);
+    let req = test::TestRequest::put()
+        .uri(&mark_uri)
+        .header(handler::REVIEWER_HEADER, "Alice")
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "PUT {} should return 200", mark_uri);
+
+    let reject_uri = format!("/exp/{}/granules/{}/reject", experiment_id, granule.id);
+    let reason = models::RejectGranule { reason: Some("Two granules merged".to_string()) };
+    let req = test::TestRequest::post()
+        .uri(&reject_uri)
+        .header(handler::REVIEWER_HEADER, "Bob")
+        .header("Content-Type", "application/json")
+        .set_payload(serde_json::to_string(&reason).unwrap())
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "POST {} should return 200", reject_uri);
+
+    // Repeating the same decision is fine, but isn't recorded again
+    let req = test::TestRequest::post()
+        .uri(&reject_uri)
+        .header(handler::REVIEWER_HEADER, "Bob")
+        .header("Content-Type", "application/json")
+        .set_payload(serde_json::to_string(&reason).unwrap())
+        .to_request();
+    let repeated: models::Granule = test::read_response_json(&mut app, req).await;
+    assert_eq!(repeated.status, models::ReviewStatus::Rejected);
+
+    // Both decisions should be in the history, oldest first
+    let history_uri = format!("/exp/{}/granules/{}/history", experiment_id, granule.id);
+    let req = test::TestRequest::get().uri(&history_uri).to_request();
+    let events: Vec<models::ReviewEvent> = test::read_response_json(&mut app, req).await;
+    assert_eq!(events.len(), 2, "Both decisions should be recorded");
+    assert_eq!(events[0].reviewer, "Alice", "First reviewer should be recorded");
+    assert_eq!(events[0].old_status, models::ReviewStatus::Unreviewed);
+    assert_eq!(events[0].new_status, models::ReviewStatus::Accepted);
+    assert_eq!(events[1].reviewer, "Bob", "Second reviewer should be recorded");
+    assert_eq!(events[1].old_status, models::ReviewStatus::Accepted);
+    assert_eq!(events[1].new_status, models::ReviewStatus::Rejected);
+    assert_eq!(events[1].reason, Some("Two granules merged".to_string()), "Reason should be recorded");
+
+    // The history of a missing granule is a 404
+    let missing_uri = format!("/exp/{}/granules/{}/history", experiment_id, i32::MAX);
+    let req = test::TestRequest::get().uri(&missing_uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "GET {} should return 404", missing_uri);
+
+    // A reviewer name too long to record is refused
+    let req = test::TestRequest::put()
+        .uri(&mark_uri)
+        .header(handler::REVIEWER_HEADER, "A".repeat(200))
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 422, "A long reviewer should return 422");
+
+    // Deleting the experiment keeps the history
+    let delete_uri = format!("/exp/{}", experiment_id);
+    let req = test::TestRequest::delete().uri(&delete_uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "DELETE {} should return 200", delete_uri);
+    let req = test::TestRequest::get().uri(&history_uri).to_request();
+    let events: Vec<models::ReviewEvent> = test::read_response_json(&mut app, req).await;
+    assert_eq!(events.len(), 2, "The history should outlive the experiment");
+    assert_eq!(events[0].experiment_id, experiment_id);
+}
+
+#[actix_rt::test]
+async fn test_granule_batch() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::add_granules)
+        .service(handler::get_granules);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
+    let experiment_id = new_experiment.id;
+
+    // A batch with a bad granule is rejected as a whole
+    let batch_uri = format!("/exp/{}/granules/batch", experiment_id);
+    let granules = vec![
+        models::CreateGranule {valid:false, area:Some(1.0)},
+        models::CreateGranule {valid:false, area:Some(-1.0)},
+        models::CreateGranule {valid:true, area:Some(2.0)},
+    ];
+    let req = test::TestRequest::post()
+        .uri(&batch_uri)
+        .header("Content-Type", "application/json")
+        .set_payload(serde_json::to_string(&granules).unwrap())
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 422, "POST {} with a bad granule should return 422", batch_uri);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(body["code"], "validation_failed");
+    let fields = body["fields"].as_object().expect("The problem should list the invalid fields");
+    assert_eq!(fields.keys().collect::<Vec<_>>(), vec!["1.area"], "The bad granule should be reported by index");
+
+    let uri = format!("/exp/{}/granules?include_total=true", experiment_id);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let page: models::Page<models::Granule> = test::read_response_json(&mut app, req).await;
+    assert_eq!(page.total, Some(0), "No granules should be created from a bad batch");
+
+    // A good batch creates every granule
+    let granules = vec![
+        models::CreateGranule {valid:false, area:Some(1.0)},
+        models::CreateGranule {valid:true, area:Some(2.0)},
+    ];
+    let req = test::TestRequest::post()
+        .uri(&batch_uri)
+        .header("Content-Type", "application/json")
+        .set_payload(serde_json::to_string(&granules).unwrap())
+        .to_request();
+    let result: models::BatchGranuleResponse = test::read_response_json(&mut app, req).await;
+    assert_eq!(result.created.len(), 2, "Both granules should be created");
+
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let page: models::Page<models::Granule> = test::read_response_json(&mut app, req).await;
+    let ids = page.items.iter().map(|granule| granule.id).collect::<Vec<i32>>();
+    assert_eq!(ids, result.created, "Created ids should be returned in order");
+
+    // Uploading to a missing experiment is a 404
+    let missing_uri = format!("/exp/{}/granules/batch", i32::MAX);
+    let req = test::TestRequest::post()
+        .uri(&missing_uri)
+        .header("Content-Type", "application/json")
+        .set_payload(serde_json::to_string(&granules).unwrap())
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "POST {} should return 404", missing_uri);
+}
+
+#[actix_rt::test]
+async fn test_granule_import() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::import_granules)
+        .service(handler::get_granules);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "New Experiment").await;
+    let experiment_id = new_experiment.id;
+
+    // An ImageJ style table with one bad row
+    let table = " ,Area,Mean,Keep\n1,12.5,200,yes\n2,n/a,180,no\n3,4.0,150,no\n";
+    let import_uri = format!("/exp/{}/granules/import?area_column=Area&valid_column=Keep", experiment_id);
+    let req = test::TestRequest::post()
+        .uri(&import_uri)
+        .header("Content-Type", "text/csv")
+        .set_payload(table)
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "POST {} should return 200", import_uri);
+    let body = test::read_body(response).await;
+    let result: models::CsvImportResponse = serde_json::from_slice(&body).expect("Unable to parse import response");
+    assert_eq!(result.created.len(), 2, "Good rows should be imported");
+    assert_eq!(result.rejected.len(), 1, "The bad row should be rejected");
+    assert_eq!(result.rejected[0].line, 3, "The bad row should be reported by line");
+
+    let uri = format!("/exp/{}/granules", experiment_id);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let page: models::Page<models::Granule> = test::read_response_json(&mut app, req).await;
+    let granules = page.items.iter().map(|granule| (granule.area, granule.valid)).collect::<Vec<(Option<f32>, bool)>>();
+    assert_eq!(granules, vec![(Some(12.5), true), (Some(4.0), false)], "Imported granules should match the table");
+
+    // A table without the mapped column is rejected
+    let import_uri = format!("/exp/{}/granules/import?area_column=Size", experiment_id);
+    let req = test::TestRequest::post()
+        .uri(&import_uri)
+        .header("Content-Type", "text/csv")
+        .set_payload(table)
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 422, "POST {} should return 422", import_uri);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(body["code"], "validation_failed");
+    assert!(body["fields"]["1"].is_array(), "The header problem should be reported on line 1");
+}
+
+#[actix_rt::test]
+async fn test_granule_export() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::add_granules)
+        .service(handler::export_granules);
+    let mut app = test::init_service(app).await;
+
+    // First we have to create a new experiment
+    let new_experiment = create_experiment(&mut app, "Export Experiment").await;
+    let experiment_id = new_experiment.id;
+
+    let batch_uri = format!("/exp/{}/granules/batch", experiment_id);
+    let granules = vec![
+        models::CreateGranule {valid:false, area:Some(1.5)},
+        models::CreateGranule {valid:true, area:Some(2.0)},
+    ];
+    let req = test::TestRequest::post()
+        .uri(&batch_uri)
+        .header("Content-Type", "application/json")
+        .set_payload(serde_json::to_string(&granules).unwrap())
+        .to_request();
+    let result: models::BatchGranuleResponse = test::read_response_json(&mut app, req).await;
+
+    // CSV with the experiment details as comments
+    let uri = format!("/exp/{}/granules.csv?metadata=true", experiment_id);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "GET {} should return 200", uri);
+    let body = test::read_body(response).await;
+    let expected = format!(
+        "# experiment_id: {id}\n# title: Export Experiment\n# author: Test Author\nid,experiment_id,area,valid,status,rejection_reason\n{},{id},1.5,false,unreviewed,\n{},{id},2,true,accepted,\n",
+        result.created[0], result.created[1], id = experiment_id
+    );
+    assert_eq!(String::from_utf8(body.to_vec()).unwrap(), expected, "CSV export should match");
+
+    // TSV without the comments
+    let uri = format!("/exp/{}/granules.tsv?valid=true", experiment_id);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 200, "GET {} should return 200", uri);
+    let body = test::read_body(response).await;
+    let expected = format!(
+        "id\texperiment_id\tarea\tvalid\tstatus\trejection_reason\n{}\t{}\t2\ttrue\taccepted\t\n",
+        result.created[1], experiment_id
+    );
+    assert_eq!(String::from_utf8(body.to_vec()).unwrap(), expected, "TSV export should match");
+
+    // Exporting a missing experiment is a 404
+    let uri = format!("/exp/{}/granules.csv", i32::MAX);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "GET {} should return 404", uri);
+}
+
+#[actix_rt::test]
+async fn test_validation_errors() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_experiment)
+        .service(handler::add_granule);
+    let mut app = test::init_service(app).await;
+
+    // A title longer than the column allows
+    let title = "x".repeat(200);
+    let author = "Test Author".to_string();
+    let new_experiment = models::CreateExperiment { title, author, ..Default::default() };
+    let new_experiment_json = serde_json::to_string(&new_experiment).unwrap();
+    let req = test::TestRequest::post()
+        .uri("/exp/")
+        .header("Content-Type", "application/json")
+        .set_payload(new_experiment_json.clone())
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 422, "POST /exp with a long title should return 422");
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert!(body["fields"]["title"].is_array(), "The title should be reported as invalid");
+
+    // A negative area
+    let new_granule = models::CreateGranule {valid:false, area:Some(-1.0)};
+    let new_granule_json = serde_json::to_string(&new_granule).unwrap();
+    let req = test::TestRequest::post()
+        .uri("/exp/1/granules")
+        .header("Content-Type", "application/json")
+        .set_payload(new_granule_json.clone())
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 422, "POST a negative area should return 422");
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert!(body["fields"]["area"].is_array(), "The area should be reported as invalid");
+}
+
+#[actix_rt::test]
+async fn test_granule_missing_experiment() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .service(handler::add_granule);
+    let mut app = test::init_service(app).await;
+
+    // The foreign key violation should be reported as a missing experiment
+    let new_granule = models::CreateGranule {valid:false, area:Some(1.0)};
+    let new_granule_json = serde_json::to_string(&new_granule).unwrap();
+    let uri = format!("/exp/{}/granules", i32::MAX);
+    let req = test::TestRequest::post()
+        .uri(&uri)
+        .header("Content-Type", "application/json")
+        .set_payload(new_granule_json.clone())
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "POST {} should return 404", uri);
+    let body = test::read_body(response).await;
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("Error should be json");
+    assert_eq!(body["code"], "referenced_item_not_found");
+}
+
+#[actix_rt::test]
+async fn test_extractor_errors() {
+    let app = App::new()
+        .data(APP_STATE.clone())
+        .app_data(handler::json_config(handler::JSON_PAYLOAD_LIMIT))
+        .app_data(handler::path_config())
+        .app_data(handler::query_config())
+        .service(handler::add_experiment)
+        .service(handler::get_granules)
+        .default_service(web::route().to(handler::not_found));
+    let mut app = test::init_service(app).await;
+
+    // Every failure should come back as a json error
+    let requests = vec![
+        (test::TestRequest::post()
+            .uri("/exp/")
+            .header("Content-Type", "application/json")
+            .set_payload("{\"title\": ")
+            .to_request(), 400, "Invalid json"),
+        (test::TestRequest::get().uri("/exp/abc/granules").to_request(), 400, "Non-integer experiment id"),
+        (test::TestRequest::get().uri("/exp/1/granules?limit=many").to_request(), 400, "Non-integer limit"),
+        (test::TestRequest::get().uri("/no/such/route").to_request(), 404, "Unknown route"),
+    ];
+    for (req, status, description) in requests {
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), status, "{} should return {}", description, status);
+        let body = test::read_body(response).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).expect("Error should be json");
+        assert!(body["detail"].is_string(), "{} should have an error message", description);
+        assert!(body["code"].is_string(), "{} should have an error code", description);
+    }
+}
+
+#[actix_rt::test]
+async fn test_problem_errors() {
+    let app = App::new()
+        .wrap(middleware::RequestContext::new(errors::ErrorFormat::Problem))
+        .data(APP_STATE.clone())
+        .service(handler::get_experiment);
+    let mut app = test::init_service(app).await;
+
+    let uri = format!("/exp/{}", i32::MAX);
+    let req = test::TestRequest::get()
+        .uri(&uri)
+        .header(middleware::REQUEST_ID_HEADER, "bug-report-1")
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "GET {} should return 404", uri);
+    assert_eq!(response.headers().get("Content-Type").unwrap(), errors::PROBLEM_JSON, "Errors should be problems");
+    assert_eq!(response.headers().get(middleware::REQUEST_ID_HEADER).unwrap(), "bug-report-1", "The request id should be echoed");
+
+    let body = test::read_body(response).await;
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("Error should be json");
+    assert_eq!(body["type"], "urn:granules:problem:experiment_not_found");
+    assert_eq!(body["title"], "Not found");
+    assert_eq!(body["status"], 404);
+    assert_eq!(body["code"], "experiment_not_found");
+    assert!(body["detail"].is_string(), "The problem should have a detail");
+    assert_eq!(body["request_id"], "bug-report-1", "The request id should be in the body");
+}
+
+#[actix_rt::test]
+async fn test_csv_payload_limit() {
+    let app = App::new()
+        .wrap(middleware::RequestContext::new(errors::ErrorFormat::Problem))
+        .data(APP_STATE.clone())
+        .app_data(web::PayloadConfig::new(16))
+        .service(handler::import_granules);
+    let mut app = test::init_service(app).await;
+
+    // A table over the configured limit is refused as a problem rather than actix's plain text
+    let uri = format!("/exp/{}/granules/import", i32::MAX);
+    let req = test::TestRequest::post()
+        .uri(&uri)
+        .header("Content-Type", "text/csv")
+        .set_payload("area\n1.0\n2.0\n3.0\n4.0\n5.0\n")
+        .to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 400, "A table over the limit should return 400");
+    assert_eq!(response.headers().get("Content-Type").unwrap(), errors::PROBLEM_JSON, "Errors should be problems");
+    assert!(response.headers().get(middleware::REQUEST_ID_HEADER).is_some(), "The request id should be sent");
+    let body = test::read_body(response).await;
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("Error should be json");
+    assert_eq!(body["code"], "bad_request");
+    assert!(body["request_id"].is_string(), "The request id should be in the body");
+}
+
+#[actix_rt::test]
+async fn test_legacy_errors() {
+    let app = App::new()
+        .wrap(middleware::RequestContext::new(errors::ErrorFormat::Legacy))
+        .data(APP_STATE.clone())
+        .service(handler::get_experiment);
+    let mut app = test::init_service(app).await;
+
+    let uri = format!("/exp/{}", i32::MAX);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let response = test::call_service(&mut app, req).await;
+    assert_eq!(response.status(), 404, "GET {} should return 404", uri);
+    assert_eq!(response.headers().get("Content-Type").unwrap(), "application/json", "Legacy errors should be plain json");
+
+    let body = test::read_body(response).await;
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("Error should be json");
+    assert!(body["error"].is_string(), "Legacy errors should have an error message");
+    assert!(body.get("code").is_none(), "Legacy errors should keep their shape");
+}
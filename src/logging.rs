@@ -0,0 +1,269 @@
+//! Build the logger from the `log` section of the configuration
+//!
+//! Records go to the terminal and, optionally, a file that is rotated once it grows too large.
+//! Both use the same format, either slog's usual terminal layout or one JSON object per line.
+
+use crate::config::{ConfigProblem, LogConfig, LogFormat, LogLevel};
+use slog::{o, Drain, Level, Logger, Never};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+type BoxedDrain = Box<dyn Drain<Ok = (), Err = Never> + Send>;
+
+/// Name of this crate at the start of module paths, which overrides can leave out
+const CRATE_NAME: &str = env!("CARGO_CRATE_NAME");
+
+/// A log file that is moved aside once it grows past a size, keeping a few of the old files
+///
+/// Writes are buffered until they are flushed, which the drains do after every record, so a
+/// record is never split between two files.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+    buffer: Vec<u8>,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file,
+            size,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Path of an old file, `path.1` being the most recent
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    /// Shift the old files along, dropping the oldest, and start an empty file
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep > 0 {
+            for index in (1..self.keep).rev() {
+                let older = self.rotated(index);
+                if older.exists() {
+                    fs::rename(older, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        // A record larger than the limit still gets a file to itself
+        if self.size > 0 && self.size + self.buffer.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(&self.buffer)?;
+        self.size += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.file.flush()
+    }
+}
+
+/// Least severe level logged for a module, from the most specific override that matches it
+fn level_for(module: &str, level: Level, modules: &HashMap<String, LogLevel>) -> Level {
+    let local = module
+        .strip_prefix(CRATE_NAME)
+        .and_then(|rest| rest.strip_prefix("::"));
+    modules
+        .iter()
+        .filter(|(name, _)| {
+            let matches = |path: &str| {
+                path == name.as_str()
+                    || path
+                        .strip_prefix(name.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            };
+            matches(module) || local.is_some_and(matches)
+        })
+        .max_by_key(|(name, _)| name.len())
+        .map_or(level, |(_, LogLevel(level))| *level)
+}
+
+/// Write records to `io` in the configured format
+///
+/// Records that can't be written, such as when the disk is full, are dropped, as a fused drain
+/// would panic the logging thread and every later log call with it.
+fn format_drain<W: Write + Send + 'static>(format: LogFormat, io: W) -> BoxedDrain {
+    match format {
+        LogFormat::Terminal => {
+            let decorator = slog_term::PlainDecorator::new(io);
+            Box::new(slog_term::FullFormat::new(decorator).build().ignore_res())
+        }
+        LogFormat::Json => Box::new(
+            slog_json::Json::new(io)
+                .add_default_keys()
+                .set_flush(true)
+                .build()
+                .ignore_res(),
+        ),
+    }
+}
+
+pub fn configure(config: &LogConfig) -> Result<Logger, ConfigProblem> {
+    let console: BoxedDrain = match config.format {
+        LogFormat::Terminal => {
+            let decorator = slog_term::TermDecorator::new().build();
+            Box::new(slog_term::FullFormat::new(decorator).build().ignore_res())
+        }
+        LogFormat::Json => format_drain(LogFormat::Json, io::stderr()),
+    };
+    build(config, Some(console))
+}
+
+/// Send records to `console`, if there is one, and to the configured file
+fn build(config: &LogConfig, console: Option<BoxedDrain>) -> Result<Logger, ConfigProblem> {
+    let file = match &config.file {
+        Some(file) => {
+            let rotating =
+                RotatingFile::open(&file.path, file.max_size, file.keep).map_err(|err| {
+                    ConfigProblem::Invalid {
+                        key: "log.file.path".to_string(),
+                        reason: format!("can't open {}: {}", file.path.display(), err),
+                    }
+                })?;
+            Some(format_drain(config.format, rotating))
+        }
+        None => None,
+    };
+    let drain: BoxedDrain = match (console, file) {
+        (Some(console), Some(file)) => Box::new(slog::Duplicate::new(console, file).ignore_res()),
+        (Some(drain), None) | (None, Some(drain)) => drain,
+        (None, None) => Box::new(slog::Discard),
+    };
+
+    let LogLevel(level) = config.level;
+    let modules = config.modules.clone();
+    let drain = slog_async::Async::new(drain)
+        .build()
+        .fuse()
+        .filter(move |record| {
+            record
+                .level()
+                .is_at_least(level_for(record.module(), level, &modules))
+        })
+        .ignore_res();
+    Ok(Logger::root(drain, o!("v" => env!("CARGO_PKG_VERSION"))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LogFileConfig;
+
+    #[test]
+    fn test_level_for_module() {
+        let mut modules = HashMap::new();
+        modules.insert("handler".to_string(), LogLevel(Level::Debug));
+        modules.insert(format!("{}::db", CRATE_NAME), LogLevel(Level::Error));
+
+        let handler = format!("{}::handler", CRATE_NAME);
+        assert_eq!(level_for(&handler, Level::Info, &modules), Level::Debug);
+        let db = format!("{}::db", CRATE_NAME);
+        assert_eq!(level_for(&db, Level::Info, &modules), Level::Error);
+        let handlers = format!("{}::handlers", CRATE_NAME);
+        assert_eq!(
+            level_for(&handlers, Level::Info, &modules),
+            Level::Info,
+            "Overrides should only match whole module names"
+        );
+    }
+
+    /// An empty directory of our own, so runs never see each other's files
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("granules-{}-{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_debug_records_logged() {
+        let dir = temp_dir("debug");
+        let path = dir.join("granules.log");
+
+        let config = LogConfig {
+            format: LogFormat::Json,
+            level: LogLevel(Level::Debug),
+            file: Some(LogFileConfig {
+                path: path.clone(),
+                max_size: 1 << 20,
+                keep: 0,
+            }),
+            ..Default::default()
+        };
+        // Only the file, so the test doesn't write to the console
+        let log = build(&config, None).unwrap();
+        slog::debug!(log, "debug record");
+        slog::trace!(log, "trace record");
+        // Dropping the logger waits for the logging thread to write everything out
+        drop(log);
+
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert!(
+            written.contains("debug record"),
+            "Debug records shouldn't be compiled out"
+        );
+        assert!(!written.contains("trace record"));
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = temp_dir("log");
+        let path = dir.join("granules.log");
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for record in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(record.as_bytes()).unwrap();
+            file.flush().unwrap();
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).ok();
+        let files = (
+            read("granules.log"),
+            read("granules.log.1"),
+            read("granules.log.2"),
+            read("granules.log.3"),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(files.0.as_deref(), Some("fourth\n"));
+        assert_eq!(files.1.as_deref(), Some("third\n"));
+        assert_eq!(files.2.as_deref(), Some("second\n"));
+        assert_eq!(
+            files.3, None,
+            "Only the configured number of old files should be kept"
+        );
+    }
+}
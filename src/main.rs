@@ -2,48 +2,132 @@ mod config;
 mod db;
 mod errors;
 mod handler;
+mod logging;
+mod middleware;
 mod models;
+mod tabular;
+mod validation;
 
-use crate::config::Config;
-use actix_rt;
-use actix_web::{App, HttpServer};
+use crate::config::{Args, Bind, Config, ConfigProblem};
+use actix_web::{web, App, HttpServer};
 use dotenv::dotenv;
 use slog::info;
+use std::io;
+use structopt::StructOpt;
+
+/// Report every configuration problem and stop
+fn exit_with(problems: Vec<ConfigProblem>) -> ! {
+    eprintln!("Invalid configuration:");
+    for problem in problems {
+        eprintln!("  {}", problem);
+    }
+    std::process::exit(2);
+}
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     // Set up the configuration
     dotenv().ok();
 
-    let config = Config::from_env().unwrap();
-    let log = config.configure_log();
-    let pool = config.configure_pool();
+    let args = Args::from_args();
+    let layers = Config::layers(&args).unwrap_or_else(|problem| exit_with(vec![problem]));
+    if args.print_config {
+        match Config::print(&layers) {
+            Ok(printed) => print!("{}", printed),
+            Err(problem) => exit_with(vec![problem]),
+        }
+    }
+    let config = Config::from_layers(&layers).unwrap_or_else(|problems| exit_with(problems));
+    if args.print_config {
+        return Ok(());
+    }
+
+    let log = config
+        .configure_log()
+        .unwrap_or_else(|problem| exit_with(vec![problem]));
+    let pool = config
+        .configure_pool()
+        .unwrap_or_else(|problem| exit_with(vec![problem]));
     let tera = config.configure_tera();
-    info!(
-        log,
-        "Starting server at http://{}:{}/", config.server.host, config.server.port
-    );
 
     // Launch the app
-    HttpServer::new(move || {
+    let server = &config.server;
+    let error_format = server.error_format;
+    let json_limit = server.json_limit;
+    let csv_limit = server.csv_limit;
+    let app_log = log.clone();
+    let mut http = HttpServer::new(move || {
         App::new()
+            .wrap(middleware::CatchPanic::new(app_log.clone()))
+            .wrap(middleware::RequestContext::new(error_format))
             .data(models::AppState {
                 pool: pool.clone(),
-                log: log.clone(),
+                log: app_log.clone(),
                 tera: tera.clone(),
             })
+            .app_data(handler::json_config(json_limit))
+            .app_data(handler::path_config())
+            .app_data(handler::query_config())
+            .app_data(web::PayloadConfig::new(csv_limit))
             .service(handler::add_experiment)
             .service(handler::get_experiments)
+            .service(handler::get_experiment)
+            .service(handler::update_experiment)
+            .service(handler::delete_experiment)
+            .service(handler::add_experiment_tag)
+            .service(handler::remove_experiment_tag)
             .service(handler::get_experiment_by_author)
+            .service(handler::search_experiments)
+            .service(handler::get_authors)
+            .service(handler::rename_author)
+            .service(handler::merge_authors)
             .service(handler::add_granule)
+            .service(handler::add_granules)
+            .service(handler::import_granules)
+            .service(handler::export_granules)
             .service(handler::get_granules)
+            .service(handler::get_granule_stats)
             .service(handler::mark_granule_valid)
+            .service(handler::accept_granule)
+            .service(handler::reject_granule)
+            .service(handler::reset_granule)
+            .service(handler::get_granule_history)
             .service(handler::status)
+            .default_service(web::route().to(handler::not_found))
     })
-    .keep_alive(10)
-    .bind(format!("{}:{}", config.server.host, config.server.port))?
-    .run()
-    .await
+    // No timeout turns keep-alive off, a timeout of 0 would keep connections open forever
+    .keep_alive(Some(server.keep_alive).filter(|&seconds| seconds > 0));
+    if let Some(workers) = server.workers {
+        http = http.workers(workers);
+    }
+    // The backlog is used when binding, so it has to be set first
+    if let Some(backlog) = server.backlog {
+        http = http.backlog(backlog);
+    }
+    if let Some(timeout) = server.client_timeout {
+        http = http.client_timeout(timeout);
+    }
+    if let Some(timeout) = server.client_shutdown {
+        http = http.client_shutdown(timeout);
+    }
+
+    for bind in server.binds() {
+        let bound = match &bind {
+            Bind::Tcp(address) => http.bind(address),
+            #[cfg(unix)]
+            Bind::Unix(path) => http.bind_uds(path),
+            #[cfg(not(unix))]
+            Bind::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unix sockets aren't supported on this platform",
+            )),
+        };
+        http = bound.map_err(|err| {
+            io::Error::new(err.kind(), format!("Can't listen on {}: {}", bind, err))
+        })?;
+        info!(log, "Starting server at {}", bind);
+    }
+    http.run().await
 }
 
 #[cfg(test)]
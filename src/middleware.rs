@@ -0,0 +1,296 @@
+//! Middleware wrapped around every request
+
+use crate::errors::{AppError, AppErrorType, ErrorContext, ErrorFormat, ERROR_CONTEXT};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{InternalError, PayloadError};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, FutureExt, LocalBoxFuture, Ready};
+use slog::{crit, o, Logger};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::task::{Context, Poll};
+use uuid::Uuid;
+
+/// Header carrying the id of a request, both from clients and in responses
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Longest request id accepted from a client
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Give every request an id and the error format its error responses use
+///
+/// The id comes from the client's `X-Request-Id` header when it is sensible, and is generated
+/// otherwise. It is echoed in a response header so it can be quoted in bug reports. Bodies that
+/// are too large or can't be read are reported as our errors too.
+pub struct RequestContext {
+    format: ErrorFormat,
+}
+
+impl RequestContext {
+    pub fn new(format: ErrorFormat) -> Self {
+        RequestContext { format }
+    }
+}
+
+impl<S, B> Transform<S> for RequestContext
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestContextMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestContextMiddleware {
+            service,
+            format: self.format,
+        })
+    }
+}
+
+pub struct RequestContextMiddleware<S> {
+    service: S,
+    format: ErrorFormat,
+}
+
+/// Use the client's request id if it is short and printable, or make a new one
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        })
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+impl<S, B> Service for RequestContextMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let context = ErrorContext {
+            request_id: request_id(&req),
+            format: self.format,
+        };
+        let header_name = HeaderName::from_static("x-request-id");
+        // Ids are either checked above or generated, so they are always valid header values
+        let header_value = HeaderValue::from_str(&context.request_id)
+            .unwrap_or_else(|_| HeaderValue::from_static("invalid"));
+        let fut = self.service.call(req);
+
+        ERROR_CONTEXT
+            .scope(context, async move {
+                match fut.await {
+                    Ok(res) => {
+                        // Body extractors such as `Bytes` can't be given an error handler like
+                        // `json_config`, so their errors are turned into ours here
+                        let payload_error = res
+                            .response()
+                            .error()
+                            .and_then(|err| err.as_error::<PayloadError>())
+                            .map(AppError::bad_request);
+                        let mut res = match payload_error {
+                            Some(err) => res.error_response(err),
+                            None => res,
+                        };
+                        res.headers_mut().insert(header_name, header_value);
+                        Ok(res)
+                    }
+                    Err(err) => {
+                        // The server would build this response outside of the request's
+                        // context, so build it here while the request id is still known
+                        let mut response = err.as_response_error().error_response();
+                        response.headers_mut().insert(header_name, header_value);
+                        Err(InternalError::from_response(err, response).into())
+                    }
+                }
+            })
+            .boxed_local()
+    }
+}
+
+/// Turn a panic in a handler into a logged 500 response
+///
+/// Without this the worker drops the connection and the client never gets a reply.
+pub struct CatchPanic {
+    log: Logger,
+}
+
+impl CatchPanic {
+    pub fn new(log: Logger) -> Self {
+        CatchPanic { log }
+    }
+}
+
+impl<S, B> Transform<S> for CatchPanic
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CatchPanicMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CatchPanicMiddleware {
+            service,
+            log: self.log.new(o!("middleware" => "catch_panic")),
+        })
+    }
+}
+
+pub struct CatchPanicMiddleware<S> {
+    service: S,
+    log: Logger,
+}
+
+/// Pull the message out of a panic, if it has one
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+impl<S, B> Service for CatchPanicMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let log = self.log.new(o!("path" => req.path().to_string()));
+
+        // The handler can panic both when the future is created and while it is polled
+        let service = &mut self.service;
+        let fut = panic::catch_unwind(AssertUnwindSafe(move || service.call(req)));
+
+        async move {
+            let result = match fut {
+                Ok(fut) => AssertUnwindSafe(fut).catch_unwind().await,
+                Err(payload) => Err(payload),
+            };
+
+            result.unwrap_or_else(|payload| {
+                let err = AppError {
+                    message: Some("Unexpected server error".to_string()),
+                    cause: Some(panic_message(&*payload)),
+                    error_type: AppErrorType::DbError,
+                    code: Some("internal_error"),
+                };
+                crit!(
+                    log.new(o!("cause" => err.cause.clone())),
+                    "Handler panicked"
+                );
+
+                // The server turns the error into the usual json response
+                Err(err.into())
+            })
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App, HttpRequest, HttpResponse};
+    use slog::Discard;
+
+    async fn panicking_handler() -> HttpResponse {
+        panic!("Handler failed")
+    }
+
+    async fn echo_request_id(_req: HttpRequest) -> HttpResponse {
+        let id = ERROR_CONTEXT
+            .try_with(|context| context.request_id.clone())
+            .unwrap_or_default();
+        HttpResponse::Ok().body(id)
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id() {
+        let app = App::new()
+            .wrap(RequestContext::new(ErrorFormat::Problem))
+            .route("/id", web::get().to(echo_request_id));
+        let mut app = test::init_service(app).await;
+
+        // A sensible id from the client is kept
+        let req = test::TestRequest::get()
+            .uri("/id")
+            .header(REQUEST_ID_HEADER, "trace-42")
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "trace-42");
+        let body = test::read_body(res).await;
+        assert_eq!(body, "trace-42", "Handlers should see the request id");
+
+        // Anything else is replaced
+        let req = test::TestRequest::get()
+            .uri("/id")
+            .header(REQUEST_ID_HEADER, "has spaces")
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        let id = res.headers().get(REQUEST_ID_HEADER).unwrap();
+        assert!(
+            Uuid::parse_str(id.to_str().unwrap()).is_ok(),
+            "A bad request id should be replaced with a uuid"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_panic_becomes_500() {
+        let app = App::new()
+            .wrap(CatchPanic::new(Logger::root(Discard, o!())))
+            .route("/panic", web::get().to(panicking_handler));
+        let mut app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/panic").to_request();
+        let err = app
+            .call(req)
+            .await
+            .expect_err("A panic should be returned as an error");
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "A panic should be a 500"
+        );
+    }
+}
@@ -1,7 +1,10 @@
 //! Models for the data structures within the database
 
+use chrono::{DateTime, NaiveDate, Utc};
 use deadpool_postgres::Pool;
-use serde::{Deserialize, Serialize};
+use postgres_types::{FromSql, ToSql};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 use slog::Logger;
 use tera::Tera;
 use tokio_pg_mapper_derive::PostgresMapper;
@@ -10,6 +13,7 @@ use tokio_pg_mapper_derive::PostgresMapper;
 pub struct AppState {
     pub pool: Pool,
     pub log: Logger,
+    #[allow(dead_code)]
     pub tera: Tera,
 }
 
@@ -23,32 +27,444 @@ pub struct Status {
 pub struct Experiment {
     pub id: i32,
     pub title: String,
+    pub author_id: i32,
+    /// Name of the author, kept alongside the id for older clients
     pub author: String,
+    pub description: Option<String>,
+    /// Day the images were taken, which may be long before the experiment was uploaded
+    pub acquired_on: Option<NaiveDate>,
+    /// Free-form conditions such as cell line, stressor and dose, always a json object
+    pub conditions: Value,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Names of the experiment's tags in alphabetical order
+    pub tags: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+/// Experiments are given to an existing author with the same name, ignoring case and spacing, or
+/// to a new author
+#[derive(Deserialize, Serialize, Default)]
 pub struct CreateExperiment {
     pub title: String,
     pub author: String,
+    pub description: Option<String>,
+    pub acquired_on: Option<NaiveDate>,
+    pub conditions: Option<Value>,
+}
+
+/// Changes to an experiment, fields that are not given keep their current value
+///
+/// The optional fields are cleared by giving them as `null`, so they are `Some(None)` rather
+/// than `None`.
+#[derive(Deserialize, Serialize, Default)]
+pub struct UpdateExperiment {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub description: Option<Option<String>>,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub acquired_on: Option<Option<NaiveDate>>,
+    /// Replaces all of the current conditions, `null` removes them all
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub conditions: Option<Option<Value>>,
+}
+
+/// Read a field that was given, keeping a `null` as `Some(None)` so it can be told apart from a
+/// missing field, which serde leaves as the default `None`
+fn present<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// Whether experiments need every requested tag or just one of them
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TagMatch {
+    #[default]
+    All,
+    Any,
+}
+
+/// Filters applied when listing experiments
+///
+/// `condition_keys` is a comma separated list of keys the conditions must all have, and
+/// `conditions` is a json object the conditions must contain, such as `{"stressor": "arsenite"}`.
+/// Tags are given by repeating `tag`, which a struct can't be read from, so they are filled in by
+/// the handler.
+#[derive(Deserialize, Default)]
+pub struct ExperimentQuery {
+    pub acquired_from: Option<NaiveDate>,
+    pub acquired_to: Option<NaiveDate>,
+    pub condition_keys: Option<String>,
+    pub conditions: Option<String>,
+    #[serde(skip)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub tag_match: TagMatch,
+}
+
+impl ExperimentQuery {
+    pub fn condition_keys(&self) -> Option<Vec<String>> {
+        self.condition_keys.as_ref().map(|keys| {
+            keys.split(',')
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty())
+                .collect()
+        })
+    }
+
+    /// The conditions filter, or `None` if it was not given or is not valid json
+    pub fn conditions(&self) -> Option<Value> {
+        self.conditions
+            .as_ref()
+            .and_then(|conditions| serde_json::from_str(conditions).ok())
+    }
+}
+
+/// Tags are matched ignoring case and surrounding whitespace
+pub fn clean_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+/// Number of search results returned when no limit is given
+pub const DEFAULT_SEARCH_LIMIT: i64 = 20;
+
+/// Free text search over experiment titles and author names
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+impl SearchQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+/// An experiment found by a search, best matches have the highest rank
+#[derive(Deserialize, Serialize)]
+pub struct ExperimentMatch {
+    #[serde(flatten)]
+    pub experiment: Experiment,
+    pub rank: f32,
+}
+
+#[derive(Deserialize, Serialize, PostgresMapper)]
+#[pg_mapper(table = "granules")]
+pub struct Author {
+    pub id: i32,
+    pub name: String,
 }
 
+/// An author along with how much work is recorded under their name
+#[derive(Deserialize, Serialize, PostgresMapper)]
+#[pg_mapper(table = "granules")]
+pub struct AuthorSummary {
+    pub id: i32,
+    pub name: String,
+    pub experiment_count: i64,
+    pub granule_count: i64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RenameAuthor {
+    pub name: String,
+}
+
+/// Authors to fold into another, such as "J. Smith" into "Jane Smith"
+#[derive(Deserialize, Serialize)]
+pub struct MergeAuthors {
+    pub from: Vec<i32>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MergeAuthorsResponse {
+    pub author: Author,
+    pub merged: Vec<i32>,
+    pub experiments_moved: u64,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteExperimentQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DeleteExperimentResponse {
+    pub experiment_id: i32,
+    pub granules_deleted: u64,
+    pub dry_run: bool,
+}
+
+/// Outcome of reviewing a granule
+#[derive(Deserialize, Serialize, ToSql, FromSql, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[postgres(name = "review_status")]
+pub enum ReviewStatus {
+    #[postgres(name = "unreviewed")]
+    Unreviewed,
+    #[postgres(name = "accepted")]
+    Accepted,
+    #[postgres(name = "rejected")]
+    Rejected,
+}
+
+impl ReviewStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReviewStatus::Unreviewed => "unreviewed",
+            ReviewStatus::Accepted => "accepted",
+            ReviewStatus::Rejected => "rejected",
+        }
+    }
+}
+
+/// A granule as stored in the database
+///
+/// `valid` is derived from the status by the database and is kept for older clients.
 #[derive(Deserialize, Serialize, PostgresMapper)]
 #[pg_mapper(table = "granules")]
 pub struct Granule {
     pub id: i32,
     pub valid: bool,
-    pub area: f32,
+    pub status: ReviewStatus,
+    pub rejection_reason: Option<String>,
+    /// Missing for detections that could not be measured, such as objects clipped at the edge
+    pub area: Option<f32>,
     pub experiment_id: i32,
 }
 
+/// Order in which granules are listed
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum GranuleSort {
+    #[default]
+    #[serde(rename = "id")]
+    Id,
+    #[serde(rename = "area")]
+    Area,
+    #[serde(rename = "-area")]
+    AreaDesc,
+}
+
+/// Filters applied when listing the granules of an experiment
+#[derive(Deserialize)]
+pub struct GranuleQuery {
+    pub valid: Option<bool>,
+    pub status: Option<ReviewStatus>,
+    pub min_area: Option<f32>,
+    pub max_area: Option<f32>,
+    /// Only granules with, or without, a measured area
+    pub has_area: Option<bool>,
+    #[serde(default)]
+    pub sort: GranuleSort,
+}
+
 #[derive(Deserialize, Serialize, PostgresMapper, Debug)]
 #[pg_mapper(table = "granules")]
 pub struct CreateGranule {
     pub valid: bool,
-    pub area: f32,
+    pub area: Option<f32>,
+}
+
+impl CreateGranule {
+    /// Status given to the granule when it is first stored
+    pub fn initial_status(&self) -> ReviewStatus {
+        if self.valid {
+            ReviewStatus::Accepted
+        } else {
+            ReviewStatus::Unreviewed
+        }
+    }
+}
+
+/// Summary of the areas of an experiment's granules
+///
+/// Granules without an area are counted in `missing_area` and left out of the area figures, which
+/// are missing when no granule has an area.
+#[derive(Deserialize, Serialize, PostgresMapper)]
+#[pg_mapper(table = "granules")]
+pub struct GranuleStats {
+    pub count: i64,
+    pub with_area: i64,
+    pub missing_area: i64,
+    pub min_area: Option<f32>,
+    pub max_area: Option<f32>,
+    pub mean_area: Option<f64>,
+}
+
+/// Result of a batch upload, either every granule is created or none are
+#[derive(Deserialize, Serialize, Default)]
+pub struct BatchGranuleResponse {
+    pub created: Vec<i32>,
+}
+
+fn default_area_column() -> String {
+    "area".to_string()
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+/// How the columns of an uploaded table map onto the fields of a granule
+#[derive(Deserialize)]
+pub struct CsvImportQuery {
+    #[serde(default = "default_area_column")]
+    pub area_column: String,
+    pub valid_column: Option<String>,
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+}
+
+/// Problems with one line of an uploaded table
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RowError {
+    pub line: u64,
+    pub errors: Vec<String>,
+}
+
+/// Result of importing a table, rows with errors are skipped
+#[derive(Deserialize, Serialize)]
+pub struct CsvImportResponse {
+    pub created: Vec<i32>,
+    pub rejected: Vec<RowError>,
+}
+
+/// Options for exporting the granules of an experiment as a table
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub metadata: bool,
+}
+
+/// Number of items returned when the client does not ask for a page size
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+/// Upper bound on the page size a client may request
+pub const MAX_PAGE_SIZE: i64 = 1000;
+
+/// Keyset pagination parameters taken from the query string
+#[derive(Deserialize)]
+pub struct PageQuery {
+    pub limit: Option<i64>,
+    pub after: Option<i32>,
+    #[serde(default)]
+    pub include_total: bool,
+}
+
+impl PageQuery {
+    /// Requested page size, clamped to a sensible range
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+/// A single page of results
+///
+/// `next_cursor` is the id to pass as `after` to fetch the following page, it is `None` on the
+/// last page.
+#[derive(Deserialize, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<i32>,
+    pub total: Option<i64>,
+}
+
+impl<T> Page<T> {
+    /// Build a page from rows fetched with one more than the `limit`
+    ///
+    /// The extra row only tells us that another page exists, so it is dropped here.
+    pub fn from_rows(mut items: Vec<T>, limit: i64, id: impl Fn(&T) -> i32) -> Self {
+        let next_cursor = if items.len() as i64 > limit {
+            items.truncate(limit as usize);
+            items.last().map(id)
+        } else {
+            None
+        };
+        Page {
+            items,
+            next_cursor,
+            total: None,
+        }
+    }
+}
+
+/// A single review decision made on a granule
+#[derive(Deserialize, Serialize, PostgresMapper)]
+#[pg_mapper(table = "granule_review_event")]
+pub struct ReviewEvent {
+    pub id: i32,
+    pub experiment_id: i32,
+    pub granule_id: i32,
+    pub reviewer: String,
+    pub created_at: DateTime<Utc>,
+    pub old_status: ReviewStatus,
+    pub new_status: ReviewStatus,
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct RejectGranule {
+    pub reason: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct ResultResponse {
     pub success: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_with_more_rows() {
+        let page = Page::from_rows(vec![1, 2, 3], 2, |id| *id);
+        assert_eq!(page.items, vec![1, 2], "Extra row should be dropped");
+        assert_eq!(page.next_cursor, Some(2), "Cursor should be the last id");
+    }
+
+    #[test]
+    fn test_last_page() {
+        let page = Page::from_rows(vec![1, 2], 2, |id| *id);
+        assert_eq!(page.items, vec![1, 2], "All rows should be kept");
+        assert_eq!(page.next_cursor, None, "Last page should have no cursor");
+    }
+
+    #[test]
+    fn test_page_limit_clamped() {
+        let query = PageQuery {
+            limit: Some(MAX_PAGE_SIZE + 1),
+            after: None,
+            include_total: false,
+        };
+        assert_eq!(query.limit(), MAX_PAGE_SIZE, "Limit should be capped");
+
+        let query = PageQuery {
+            limit: Some(0),
+            after: None,
+            include_total: false,
+        };
+        assert_eq!(query.limit(), 1, "Limit should be at least one");
+    }
+}
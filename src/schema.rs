@@ -1,23 +1,71 @@
+table! {
+    author (id) {
+        id -> Int4,
+        name -> Varchar,
+    }
+}
+
 table! {
     experiment (id) {
         id -> Int4,
         title -> Varchar,
-        author -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        description -> Nullable<Text>,
+        acquired_on -> Nullable<Date>,
+        conditions -> Jsonb,
+        author_id -> Int4,
+    }
+}
+
+table! {
+    experiment_tag (experiment_id, tag_id) {
+        experiment_id -> Int4,
+        tag_id -> Int4,
     }
 }
 
 table! {
     granule (id) {
         id -> Int4,
-        valid -> Bool,
         area -> Nullable<Float4>,
         experiment_id -> Int4,
+        status -> Review_status,
+        rejection_reason -> Nullable<Text>,
+        valid -> Nullable<Bool>,
+    }
+}
+
+table! {
+    granule_review_event (id) {
+        id -> Int4,
+        experiment_id -> Int4,
+        granule_id -> Int4,
+        reviewer -> Varchar,
+        created_at -> Timestamptz,
+        old_status -> Review_status,
+        new_status -> Review_status,
+        reason -> Nullable<Text>,
+    }
+}
+
+table! {
+    tag (id) {
+        id -> Int4,
+        name -> Varchar,
     }
 }
 
+joinable!(experiment -> author (author_id));
+joinable!(experiment_tag -> experiment (experiment_id));
+joinable!(experiment_tag -> tag (tag_id));
 joinable!(granule -> experiment (experiment_id));
 
 allow_tables_to_appear_in_same_query!(
+    author,
     experiment,
+    experiment_tag,
     granule,
+    granule_review_event,
+    tag,
 );
@@ -0,0 +1,295 @@
+//! Read and write granules as delimited text files, such as the CSV tables produced by ImageJ
+//! or CellProfiler
+
+use crate::models::{CreateGranule, CsvImportQuery, Experiment, Granule, RowError};
+use crate::validation::Validate;
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+
+/// Columns written when exporting granules, a missing area is written as an empty cell
+const GRANULE_COLUMNS: [&str; 6] = [
+    "id",
+    "experiment_id",
+    "area",
+    "valid",
+    "status",
+    "rejection_reason",
+];
+
+/// Granules read from a file, along with the rows that could not be used
+pub struct ParsedRows {
+    pub granules: Vec<CreateGranule>,
+    pub errors: Vec<RowError>,
+}
+
+/// Find a column by name, ignoring case and surrounding whitespace
+fn find_column(headers: &StringRecord, name: &str) -> Option<usize> {
+    headers
+        .iter()
+        .position(|header| header.trim().eq_ignore_ascii_case(name.trim()))
+}
+
+/// Whether a cell is one of the ways a spreadsheet or R writes a missing number
+fn is_missing(value: &str) -> bool {
+    let value = value.trim();
+    value.is_empty() || value.eq_ignore_ascii_case("na")
+}
+
+/// Interpret the common ways a spreadsheet writes a boolean
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "t" | "yes" | "y" | "1" => Some(true),
+        "false" | "f" | "no" | "n" | "0" | "" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_row(
+    record: &StringRecord,
+    area_index: usize,
+    valid_index: Option<usize>,
+) -> Result<CreateGranule, Vec<String>> {
+    let mut errors = Vec::new();
+
+    let area = match record.get(area_index) {
+        Some(value) if is_missing(value) => None,
+        Some(value) => match value.trim().parse::<f32>() {
+            Ok(area) => Some(area),
+            Err(_) => {
+                errors.push(format!("area \"{}\" is not a number", value));
+                None
+            }
+        },
+        None => {
+            errors.push("area column is missing".to_string());
+            None
+        }
+    };
+
+    let valid = match valid_index.map(|index| record.get(index).map(parse_bool)) {
+        None => false,
+        Some(Some(Some(valid))) => valid,
+        Some(Some(None)) => {
+            errors.push(format!(
+                "valid \"{}\" is not a boolean",
+                valid_index
+                    .and_then(|index| record.get(index))
+                    .unwrap_or_default()
+            ));
+            false
+        }
+        Some(None) => {
+            errors.push("valid column is missing".to_string());
+            false
+        }
+    };
+
+    let granule = CreateGranule { valid, area };
+    for (field, messages) in granule.field_errors() {
+        errors.extend(
+            messages
+                .iter()
+                .map(|message| format!("{} {}", field, message)),
+        );
+    }
+    if errors.is_empty() {
+        Ok(granule)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Read granules from a delimited file with a header row
+///
+/// Rows that cannot be read are collected with their line number rather than stopping the
+/// import, an error is only returned when the header itself is unusable.
+pub fn parse_granules(data: &[u8], mapping: &CsvImportQuery) -> Result<ParsedRows, RowError> {
+    let mut reader = ReaderBuilder::new()
+        .delimiter(mapping.delimiter as u8)
+        .flexible(true)
+        .from_reader(data);
+
+    let header_error = |message: String| RowError {
+        line: 1,
+        errors: vec![message],
+    };
+    let headers = reader
+        .headers()
+        .map_err(|err| header_error(err.to_string()))?
+        .clone();
+    let area_index = find_column(&headers, &mapping.area_column)
+        .ok_or_else(|| header_error(format!("No column named \"{}\"", mapping.area_column)))?;
+    let valid_index = match &mapping.valid_column {
+        Some(name) => Some(
+            find_column(&headers, name)
+                .ok_or_else(|| header_error(format!("No column named \"{}\"", name)))?,
+        ),
+        None => None,
+    };
+
+    let mut parsed = ParsedRows {
+        granules: Vec::new(),
+        errors: Vec::new(),
+    };
+    for record in reader.records() {
+        match record {
+            Ok(record) => {
+                let line = record.position().map(|pos| pos.line()).unwrap_or_default();
+                match parse_row(&record, area_index, valid_index) {
+                    Ok(granule) => parsed.granules.push(granule),
+                    Err(errors) => parsed.errors.push(RowError { line, errors }),
+                }
+            }
+            Err(err) => {
+                let line = err.position().map(|pos| pos.line()).unwrap_or_default();
+                parsed.errors.push(RowError {
+                    line,
+                    errors: vec![err.to_string()],
+                });
+            }
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Experiment details as comment lines, to be skipped with `comment="#"` in pandas or
+/// `comment.char = "#"` in R
+pub fn metadata_lines(experiment: &Experiment) -> String {
+    // Keep each value on its own line
+    let clean = |value: &str| value.replace(&['\r', '\n'][..], " ");
+    let mut lines = format!(
+        "# experiment_id: {}\n# title: {}\n# author: {}\n",
+        experiment.id,
+        clean(&experiment.title),
+        clean(&experiment.author)
+    );
+    if let Some(acquired_on) = experiment.acquired_on {
+        lines.push_str(&format!("# acquired_on: {}\n", acquired_on));
+    }
+    if experiment
+        .conditions
+        .as_object()
+        .is_some_and(|conditions| !conditions.is_empty())
+    {
+        lines.push_str(&format!("# conditions: {}\n", experiment.conditions));
+    }
+    lines
+}
+
+/// Write granules as delimited rows, optionally preceded by a header row
+pub fn write_granules(
+    granules: &[Granule],
+    delimiter: u8,
+    header: bool,
+) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(Vec::new());
+
+    if header {
+        writer.write_record(GRANULE_COLUMNS)?;
+    }
+    for granule in granules {
+        writer.write_record(&[
+            granule.id.to_string(),
+            granule.experiment_id.to_string(),
+            granule
+                .area
+                .map(|area| area.to_string())
+                .unwrap_or_default(),
+            granule.valid.to_string(),
+            granule.status.as_str().to_string(),
+            granule.rejection_reason.clone().unwrap_or_default(),
+        ])?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|err| csv::Error::from(err.into_error()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ReviewStatus;
+
+    fn mapping(area_column: &str, valid_column: Option<&str>) -> CsvImportQuery {
+        CsvImportQuery {
+            area_column: area_column.to_string(),
+            valid_column: valid_column.map(|name| name.to_string()),
+            delimiter: ',',
+        }
+    }
+
+    #[test]
+    fn test_parse_mapped_columns() {
+        let data = b" ,Area,Mean,Valid\n1,12.5,200,yes\n2,3,180,0\n";
+        let parsed = parse_granules(data, &mapping("area", Some("valid"))).unwrap();
+        assert!(parsed.errors.is_empty(), "All rows should be read");
+        assert_eq!(parsed.granules.len(), 2);
+        assert_eq!(parsed.granules[0].area, Some(12.5));
+        assert!(parsed.granules[0].valid);
+        assert!(!parsed.granules[1].valid);
+    }
+
+    #[test]
+    fn test_bad_rows_reported_by_line() {
+        let data = b"Area\n1.0\nlarge\n-2\n4.0\n";
+        let parsed = parse_granules(data, &mapping("Area", None)).unwrap();
+        assert_eq!(parsed.granules.len(), 2, "Good rows should still be read");
+        let lines = parsed
+            .errors
+            .iter()
+            .map(|err| err.line)
+            .collect::<Vec<u64>>();
+        assert_eq!(lines, vec![3, 4], "Bad rows should be reported by line");
+    }
+
+    #[test]
+    fn test_write_tsv() {
+        let granules = vec![Granule {
+            id: 1,
+            valid: false,
+            status: ReviewStatus::Rejected,
+            rejection_reason: Some("Out of focus".to_string()),
+            area: Some(2.5),
+            experiment_id: 3,
+        }];
+        let data = write_granules(&granules, b'\t', true).unwrap();
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "id\texperiment_id\tarea\tvalid\tstatus\trejection_reason\n1\t3\t2.5\tfalse\trejected\tOut of focus\n"
+        );
+    }
+
+    #[test]
+    fn test_missing_areas() {
+        let data = b"Area,Valid\n1.0,yes\n,no\nNA,no\n";
+        let parsed = parse_granules(data, &mapping("area", Some("valid"))).unwrap();
+        assert!(parsed.errors.is_empty(), "Missing areas are not errors");
+        let areas = parsed
+            .granules
+            .iter()
+            .map(|granule| granule.area)
+            .collect::<Vec<Option<f32>>>();
+        assert_eq!(areas, vec![Some(1.0), None, None]);
+
+        let granules = vec![Granule {
+            id: 1,
+            valid: false,
+            status: ReviewStatus::Unreviewed,
+            rejection_reason: None,
+            area: None,
+            experiment_id: 3,
+        }];
+        let data = write_granules(&granules, b',', false).unwrap();
+        assert_eq!(String::from_utf8(data).unwrap(), "1,3,,false,unreviewed,\n");
+    }
+
+    #[test]
+    fn test_missing_column() {
+        let data = b"Mean\n1.0\n";
+        let result = parse_granules(data, &mapping("Area", None));
+        assert!(result.is_err(), "A missing area column should be an error");
+    }
+}
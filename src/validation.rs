@@ -0,0 +1,328 @@
+//! Check incoming requests before they reach the database
+//!
+//! Problems are collected for every field, so a client can fix them all in one go.
+
+use crate::errors::{AppError, FieldErrors};
+use crate::models::{
+    CreateExperiment, CreateGranule, ExperimentQuery, GranuleQuery, MergeAuthors, RenameAuthor,
+    SearchQuery, UpdateExperiment,
+};
+use serde_json::Value;
+
+/// Longest title or author the experiment table can store
+pub const MAX_NAME_LENGTH: usize = 150;
+
+/// Longest tag the tag table can store
+pub const MAX_TAG_LENGTH: usize = 50;
+
+pub trait Validate {
+    /// Messages for every invalid field, empty when the request is valid
+    fn field_errors(&self) -> FieldErrors;
+
+    fn validate(&self) -> Result<(), AppError> {
+        let fields = self.field_errors();
+        if fields.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::validation_error(fields))
+        }
+    }
+}
+
+fn add_error(fields: &mut FieldErrors, field: &str, message: &str) {
+    fields
+        .entry(field.to_string())
+        .or_default()
+        .push(message.to_string());
+}
+
+fn check_name(fields: &mut FieldErrors, field: &str, value: &str) {
+    if value.trim().is_empty() {
+        add_error(fields, field, "must not be empty");
+    }
+    if value.chars().count() > MAX_NAME_LENGTH {
+        add_error(
+            fields,
+            field,
+            &format!("must be at most {} characters", MAX_NAME_LENGTH),
+        );
+    }
+}
+
+fn check_tag(fields: &mut FieldErrors, field: &str, tag: &str) {
+    if tag.is_empty() {
+        add_error(fields, field, "must not be empty");
+    }
+    if tag.chars().count() > MAX_TAG_LENGTH {
+        add_error(
+            fields,
+            field,
+            &format!("must be at most {} characters", MAX_TAG_LENGTH),
+        );
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_alphanumeric() || "-_.".contains(c))
+    {
+        add_error(
+            fields,
+            field,
+            "must only contain letters, numbers, '-', '_' or '.'",
+        );
+    }
+}
+
+/// Check a tag given in the path rather than in a json body
+pub fn validate_tag(tag: &str) -> Result<(), AppError> {
+    let mut fields = FieldErrors::new();
+    check_tag(&mut fields, "tag", tag);
+    if fields.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::validation_error(fields))
+    }
+}
+
+/// Check the reviewer named in a request header, which is stored alongside each decision
+pub fn validate_reviewer(reviewer: &str) -> Result<(), AppError> {
+    let mut fields = FieldErrors::new();
+    check_name(&mut fields, "reviewer", reviewer);
+    if fields.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::validation_error(fields))
+    }
+}
+
+fn check_area(fields: &mut FieldErrors, field: &str, area: f32) {
+    if !area.is_finite() || area < 0.0 {
+        add_error(fields, field, "must be a non-negative number");
+    }
+}
+
+fn check_conditions(fields: &mut FieldErrors, field: &str, conditions: &Value) {
+    if !conditions.is_object() {
+        add_error(fields, field, "must be a json object");
+    }
+}
+
+impl Validate for CreateExperiment {
+    fn field_errors(&self) -> FieldErrors {
+        let mut fields = FieldErrors::new();
+        check_name(&mut fields, "title", &self.title);
+        check_name(&mut fields, "author", &self.author);
+        if let Some(conditions) = &self.conditions {
+            check_conditions(&mut fields, "conditions", conditions);
+        }
+        fields
+    }
+}
+
+impl Validate for UpdateExperiment {
+    fn field_errors(&self) -> FieldErrors {
+        let mut fields = FieldErrors::new();
+        if let Some(title) = &self.title {
+            check_name(&mut fields, "title", title);
+        }
+        if let Some(author) = &self.author {
+            check_name(&mut fields, "author", author);
+        }
+        if let Some(Some(conditions)) = &self.conditions {
+            check_conditions(&mut fields, "conditions", conditions);
+        }
+        fields
+    }
+}
+
+impl Validate for RenameAuthor {
+    fn field_errors(&self) -> FieldErrors {
+        let mut fields = FieldErrors::new();
+        check_name(&mut fields, "name", &self.name);
+        fields
+    }
+}
+
+impl Validate for MergeAuthors {
+    fn field_errors(&self) -> FieldErrors {
+        let mut fields = FieldErrors::new();
+        if self.from.is_empty() {
+            add_error(&mut fields, "from", "must name at least one author");
+        }
+        fields
+    }
+}
+
+impl Validate for SearchQuery {
+    fn field_errors(&self) -> FieldErrors {
+        let mut fields = FieldErrors::new();
+        check_name(&mut fields, "q", &self.q);
+        fields
+    }
+}
+
+impl Validate for ExperimentQuery {
+    fn field_errors(&self) -> FieldErrors {
+        let mut fields = FieldErrors::new();
+        if let (Some(from), Some(to)) = (self.acquired_from, self.acquired_to) {
+            if from > to {
+                add_error(
+                    &mut fields,
+                    "acquired_from",
+                    "must not be after acquired_to",
+                );
+            }
+        }
+        if self.conditions.is_some() {
+            match self.conditions() {
+                Some(conditions) => check_conditions(&mut fields, "conditions", &conditions),
+                None => add_error(&mut fields, "conditions", "must be valid json"),
+            }
+        }
+        for tag in &self.tags {
+            check_tag(&mut fields, "tag", tag);
+        }
+        fields
+    }
+}
+
+impl Validate for CreateGranule {
+    fn field_errors(&self) -> FieldErrors {
+        let mut fields = FieldErrors::new();
+        if let Some(area) = self.area {
+            check_area(&mut fields, "area", area);
+        }
+        fields
+    }
+}
+
+/// Problems with the granules of a batch, keyed by the granule's index such as `3.area`
+impl Validate for [CreateGranule] {
+    fn field_errors(&self) -> FieldErrors {
+        self.iter()
+            .enumerate()
+            .flat_map(|(index, granule)| {
+                granule
+                    .field_errors()
+                    .into_iter()
+                    .map(move |(field, messages)| (format!("{}.{}", index, field), messages))
+            })
+            .collect()
+    }
+}
+
+impl Validate for GranuleQuery {
+    fn field_errors(&self) -> FieldErrors {
+        let mut fields = FieldErrors::new();
+        if let (Some(min_area), Some(max_area)) = (self.min_area, self.max_area) {
+            if min_area > max_area {
+                add_error(&mut fields, "min_area", "must not be greater than max_area");
+            }
+        }
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_experiment_names() {
+        let experiment = CreateExperiment {
+            title: "x".repeat(MAX_NAME_LENGTH + 1),
+            author: " ".to_string(),
+            ..Default::default()
+        };
+        let fields = experiment.field_errors();
+        assert!(
+            fields.contains_key("title"),
+            "Long titles should be rejected"
+        );
+        assert!(
+            fields.contains_key("author"),
+            "Blank authors should be rejected"
+        );
+    }
+
+    #[test]
+    fn test_experiment_conditions() {
+        let experiment = CreateExperiment {
+            title: "Arsenite".to_string(),
+            author: "Test Author".to_string(),
+            conditions: Some(serde_json::json!(["arsenite"])),
+            ..Default::default()
+        };
+        assert!(
+            experiment.field_errors().contains_key("conditions"),
+            "Conditions should be an object"
+        );
+
+        let query = ExperimentQuery {
+            conditions: Some("{\"stressor\":".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            query.field_errors().contains_key("conditions"),
+            "Invalid json should be rejected"
+        );
+
+        let query = ExperimentQuery {
+            acquired_from: chrono::NaiveDate::from_ymd_opt(2021, 3, 2),
+            acquired_to: chrono::NaiveDate::from_ymd_opt(2021, 3, 1),
+            condition_keys: Some("cell_line, dose,".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            query.field_errors().contains_key("acquired_from"),
+            "Date ranges should not be reversed"
+        );
+        assert_eq!(
+            query.condition_keys(),
+            Some(vec!["cell_line".to_string(), "dose".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_tags() {
+        assert!(validate_tag("heat-shock").is_ok());
+        assert!(validate_tag("").is_err(), "Empty tags should be rejected");
+        assert!(
+            validate_tag("heat shock").is_err(),
+            "Tags should be a single word"
+        );
+        assert!(
+            validate_tag(&"x".repeat(MAX_TAG_LENGTH + 1)).is_err(),
+            "Long tags should be rejected"
+        );
+    }
+
+    #[test]
+    fn test_granule_area() {
+        for area in &[-1.0, f32::NAN, f32::INFINITY] {
+            let granule = CreateGranule {
+                valid: false,
+                area: Some(*area),
+            };
+            assert!(
+                granule.validate().is_err(),
+                "Area {} should be rejected",
+                area
+            );
+        }
+
+        let granule = CreateGranule {
+            valid: false,
+            area: Some(0.0),
+        };
+        assert!(granule.validate().is_ok(), "Zero area should be allowed");
+
+        let granule = CreateGranule {
+            valid: false,
+            area: None,
+        };
+        assert!(
+            granule.validate().is_ok(),
+            "A missing area should be allowed"
+        );
+    }
+}
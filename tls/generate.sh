@@ -0,0 +1,45 @@
+#!/bin/sh
+# Make a certificate authority, a server certificate for localhost and a client certificate for
+# the actix user, for trying TLS against a local Postgres:
+#
+#     tls/generate.sh
+#     docker-compose -f docker-compose.yml -f docker-compose.tls.yml up
+#     env PG.HOST=localhost PG.SSLMODE=require PG.SSLROOTCERT=tls/certs/ca.crt \
+#         PG.SSLCERT=tls/certs/client.crt PG.SSLKEY=tls/certs/client.key \
+#         cargo test --features integration
+#
+# rustls only checks the server's name against its subjectAltName, ignoring the common name, so
+# the server certificate carries DNS:localhost and PG.HOST has to be localhost rather than
+# 127.0.0.1. The server certificate also has to be signed by the CA rather than being the CA
+# certificate itself, which webpki refuses with CaUsedAsEndEntity.
+#
+# The certificates are written to tls/certs, or the directory given as the first argument, which
+# is also how the test fixtures in tls/fixtures are made.
+set -eu
+
+dir=${1:-$(dirname "$0")/certs}
+days=${DAYS:-3650}
+mkdir -p "$dir"
+cd "$dir"
+
+openssl req -x509 -newkey rsa:2048 -nodes -days "$days" \
+    -keyout ca.key -out ca.crt -subj "/CN=granules test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+sign() {
+    name=$1
+    subject=$2
+    extensions=$3
+    openssl req -newkey rsa:2048 -nodes -keyout "$name.key" -out "$name.csr" -subj "$subject"
+    printf '%s\n' "basicConstraints=CA:FALSE" "$extensions" > "$name.ext"
+    openssl x509 -req -in "$name.csr" -CA ca.crt -CAkey ca.key -CAcreateserial -days "$days" \
+        -extfile "$name.ext" -out "$name.crt"
+    rm "$name.csr" "$name.ext"
+}
+
+sign server "/CN=localhost" "subjectAltName=DNS:localhost"
+# Postgres matches the common name against the user when it checks client certificates
+sign client "/CN=actix" "extendedKeyUsage=clientAuth"
+
+rm -f ca.srl
+chmod 600 ca.key server.key client.key